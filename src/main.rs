@@ -1,7 +1,6 @@
 #[macro_use]
 extern crate log;
 
-
 pub mod objects;
 pub mod renders;
 pub mod routes;
@@ -16,6 +15,13 @@ async fn main() {
     // Create local settings
     let cfg = settings::LocalConfig::init();
 
+    // Subcommand: import <paths...>
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("import") {
+        utils::run_import(&cfg, &args[2..]).await;
+        return;
+    };
+
     #[cfg(feature = "with_peace")]
     // Create database object includes postgres and redis pool
     let database = peace_database::Database::new(
@@ -0,0 +1,343 @@
+use {
+    async_trait::async_trait,
+    peace_performance::{Beatmap as PPbeatmap, PpResult},
+    serde_json::{json, Value},
+    std::{collections::BTreeMap, sync::Arc},
+    tokio::sync::RwLock,
+};
+
+use crate::objects::calculator::{self, CalcData};
+#[cfg(feature = "peace_performance_v0_3")]
+use crate::objects::peace_performance_prev;
+use crate::settings::model::{Algorithms, FormulaVariant};
+
+/// Name of the algorithm this build of peace-performance calculates
+pub const PEACE_PERFORMANCE: &str = "peace_performance_v0_4";
+pub const RELAX: &str = "relax";
+pub const AUTOPILOT: &str = "autopilot";
+
+/// Names plugins can not take
+#[inline(always)]
+pub fn is_reserved_name(name: &str) -> bool {
+    #[cfg(feature = "peace_performance_v0_3")]
+    let previous = name == peace_performance_prev::PEACE_PERFORMANCE_V0_3;
+    #[cfg(not(feature = "peace_performance_v0_3"))]
+    let previous = false;
+    previous || matches!(name, PEACE_PERFORMANCE | RELAX | AUTOPILOT | "default")
+}
+
+/// Relax (RX) and Autopilot (AP) mods
+pub const RELAX_MOD: u32 = 128;
+pub const AUTOPILOT_MOD: u32 = 8192;
+
+/// Exponent osu!std sums its pp components with
+const OSU_PP_SUM_EXPONENT: f32 = 1.1;
+
+/// A pp algorithm, requests pick one with `algo=`
+#[async_trait]
+pub trait PPAlgorithm: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn description(&self) -> &str;
+
+    /// Err: this algorithm can not calculate the request
+    async fn calculate(&self, beatmap: &PPbeatmap, data: &CalcData) -> Result<PpResult, String>;
+}
+
+/// The peace-performance version (and features) pp-server is compiled with
+pub struct PeacePerformance;
+
+#[async_trait]
+impl PPAlgorithm for PeacePerformance {
+    #[inline(always)]
+    fn name(&self) -> &str {
+        PEACE_PERFORMANCE
+    }
+
+    #[inline(always)]
+    fn description(&self) -> &str {
+        "peace-performance 0.4, the built-in calculator"
+    }
+
+    #[inline(always)]
+    async fn calculate(&self, beatmap: &PPbeatmap, data: &CalcData) -> Result<PpResult, String> {
+        Ok(calculator::calculate_pp(beatmap, data).await)
+    }
+}
+
+/// osu!std formula with reweighted components, for Relax and Autopilot plays.
+///
+/// Calculated with the built-in algorithm without the RX / AP mods, then the total is
+/// scaled by how the weighted aim / speed / acc sum compares to the normal sum
+/// (so NF, SO and other multipliers stay), with an extra penalty per miss.
+/// Other modes are calculated as usual.
+pub struct OsuFormulaVariant {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub settings: FormulaVariant,
+}
+
+#[inline(always)]
+fn sum_components(aim: f32, spd: f32, acc: f32) -> f32 {
+    (aim.powf(OSU_PP_SUM_EXPONENT) + spd.powf(OSU_PP_SUM_EXPONENT) + acc.powf(OSU_PP_SUM_EXPONENT))
+        .powf(1.0 / OSU_PP_SUM_EXPONENT)
+}
+
+#[async_trait]
+impl PPAlgorithm for OsuFormulaVariant {
+    #[inline(always)]
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    #[inline(always)]
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    async fn calculate(&self, beatmap: &PPbeatmap, data: &CalcData) -> Result<PpResult, String> {
+        let base_data = CalcData {
+            mods: data.mods.map(|m| m & !(RELAX_MOD | AUTOPILOT_MOD)),
+            ..data.clone()
+        };
+        let mut result = calculator::calculate_pp(beatmap, &base_data).await;
+        if calculator::calc_mode(beatmap, data) != 0 {
+            return Ok(result);
+        };
+
+        let s = &self.settings;
+        let (aim, spd, acc) = (
+            result.raw.aim.unwrap_or(0.0),
+            result.raw.spd.unwrap_or(0.0),
+            result.raw.acc.unwrap_or(0.0),
+        );
+        let normal = sum_components(aim, spd, acc);
+        let (aim, spd, acc) = (aim * s.aim_weight, spd * s.speed_weight, acc * s.acc_weight);
+        let weighted = sum_components(aim, spd, acc);
+        let miss_factor = s.miss_penalty.powi(data.miss.unwrap_or(0) as i32);
+
+        let total = if normal > 0.0 {
+            result.raw.total * weighted / normal * miss_factor * s.multiplier
+        } else {
+            0.0
+        };
+        result.raw.aim = Some(aim);
+        result.raw.spd = Some(spd);
+        result.raw.acc = Some(acc);
+        result.raw.total = total;
+        result.pp = total;
+        Ok(result)
+    }
+}
+
+/// All algorithms this server can calculate with.
+///
+/// One build can only link one feature set of a peace-performance version
+/// (cargo unifies features), so other versions come in as renamed dependencies
+/// or as formula variants, each registered with its own name.
+pub struct AlgorithmRegistry {
+    pub default: String,
+    /// Use relax / autopilot for RX / AP plays when no algorithm is requested
+    pub auto_select_by_mods: bool,
+    algorithms: RwLock<BTreeMap<String, Arc<dyn PPAlgorithm>>>,
+}
+
+impl AlgorithmRegistry {
+    pub fn new(settings: &Algorithms, osu_files_dir: &String) -> Self {
+        let mut algorithms: BTreeMap<String, Arc<dyn PPAlgorithm>> = BTreeMap::new();
+        algorithms.insert(PEACE_PERFORMANCE.to_string(), Arc::new(PeacePerformance));
+        #[cfg(feature = "peace_performance_v0_3")]
+        algorithms.insert(
+            peace_performance_prev::PEACE_PERFORMANCE_V0_3.to_string(),
+            Arc::new(peace_performance_prev::PeacePerformanceV0_3 {
+                osu_files_dir: osu_files_dir.clone(),
+            }),
+        );
+        if settings.relax.enabled {
+            algorithms.insert(
+                RELAX.to_string(),
+                Arc::new(OsuFormulaVariant {
+                    name: RELAX,
+                    description: "Relax (RX) plays, osu!std components reweighted",
+                    settings: settings.relax.clone(),
+                }),
+            );
+        };
+        if settings.autopilot.enabled {
+            algorithms.insert(
+                AUTOPILOT.to_string(),
+                Arc::new(OsuFormulaVariant {
+                    name: AUTOPILOT,
+                    description: "Autopilot (AP) plays, osu!std components reweighted",
+                    settings: settings.autopilot.clone(),
+                }),
+            );
+        };
+        Self {
+            default: settings.default.clone(),
+            auto_select_by_mods: settings.auto_select_by_mods,
+            algorithms: RwLock::new(algorithms),
+        }
+    }
+
+    /// Add an algorithm, returns the one it replaces
+    pub async fn register(&self, algorithm: Arc<dyn PPAlgorithm>) -> Option<Arc<dyn PPAlgorithm>> {
+        self.algorithms
+            .write()
+            .await
+            .insert(algorithm.name().to_string(), algorithm)
+    }
+
+    /// Built-in algorithms can not be removed
+    pub async fn unregister(&self, name: &str) -> Option<Arc<dyn PPAlgorithm>> {
+        if is_reserved_name(name) {
+            return None;
+        };
+        self.algorithms.write().await.remove(name)
+    }
+
+    /// None or "default" is the configured default algorithm,
+    /// which falls back to the built-in one if it is not registered
+    pub async fn get(&self, name: Option<&str>) -> Option<Arc<dyn PPAlgorithm>> {
+        let algorithms = self.algorithms.read().await;
+        match name {
+            None | Some("default") => algorithms
+                .get(&self.default)
+                .or_else(|| algorithms.get(PEACE_PERFORMANCE))
+                .cloned(),
+            Some(name) => algorithms.get(name).cloned(),
+        }
+    }
+
+    /// The requested algorithm; if none is requested, relax / autopilot for RX / AP plays
+    /// (when enabled), else the default one
+    pub async fn for_request(&self, data: &CalcData) -> Option<Arc<dyn PPAlgorithm>> {
+        if data.algo.is_some() {
+            return self.get(data.algo.as_deref()).await;
+        };
+        let mods = data.mods.unwrap_or(0);
+        if self.auto_select_by_mods {
+            let by_mods = if mods & RELAX_MOD > 0 {
+                Some(RELAX)
+            } else if mods & AUTOPILOT_MOD > 0 {
+                Some(AUTOPILOT)
+            } else {
+                None
+            };
+            if let Some(name) = by_mods {
+                // Disabled variants fall back to the default algorithm
+                if let Some(algorithm) = self.get(Some(name)).await {
+                    return Some(algorithm);
+                };
+            };
+        };
+        self.get(None).await
+    }
+
+    #[inline(always)]
+    pub async fn default(&self) -> Arc<dyn PPAlgorithm> {
+        self.get(None)
+            .await
+            .unwrap_or_else(|| Arc::new(PeacePerformance))
+    }
+
+    #[inline(always)]
+    pub async fn names(&self) -> Vec<String> {
+        self.algorithms.read().await.keys().cloned().collect()
+    }
+
+    pub async fn list(&self) -> Value {
+        let algorithms = self.algorithms.read().await;
+        json!({
+            "default": self.default,
+            "auto_select_by_mods": self.auto_select_by_mods,
+            "algorithms": algorithms.values().map(|a| json!({
+                "name": a.name(),
+                "description": a.description(),
+            })).collect::<Vec<Value>>(),
+        })
+    }
+}
+
+/// pp at 95, 98, 99, 100 acc with any algorithm
+pub async fn calculate_acc_list(
+    algorithm: &dyn PPAlgorithm,
+    beatmap: &PPbeatmap,
+    data: &CalcData,
+) -> Result<Value, String> {
+    let mut list = serde_json::Map::new();
+    for acc in [95.0f32, 98.0, 99.0, 100.0].iter() {
+        let acc_data = CalcData {
+            acc: Some(*acc),
+            n300: None,
+            n100: None,
+            n50: None,
+            katu: None,
+            miss: None,
+            combo: None,
+            passed_obj: None,
+            ..data.clone()
+        };
+        let r = algorithm.calculate(beatmap, &acc_data).await?;
+        list.insert(acc.to_string(), json!(r.pp()));
+    }
+    Ok(Value::Object(list))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Plugin(&'static str);
+
+    #[async_trait]
+    impl PPAlgorithm for Plugin {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn description(&self) -> &str {
+            "test plugin"
+        }
+
+        async fn calculate(&self, _: &PPbeatmap, _: &CalcData) -> Result<PpResult, String> {
+            Err("not calculated".to_string())
+        }
+    }
+
+    fn registry() -> AlgorithmRegistry {
+        let variant = json!({
+            "enabled": true,
+            "aim_weight": 1.0,
+            "speed_weight": 1.0,
+            "acc_weight": 1.0,
+            "miss_penalty": 1.0,
+            "multiplier": 1.0,
+        });
+        let settings: Algorithms = serde_json::from_value(json!({
+            "default": PEACE_PERFORMANCE,
+            "auto_select_by_mods": true,
+            "relax": variant,
+            "autopilot": variant,
+            "plugins_dir": "plugins",
+            "watch_plugins_dir": false,
+            "watch_plugins_debounce": 1,
+            "plugin_max_operations": 1000,
+        }))
+        .unwrap();
+        AlgorithmRegistry::new(&settings, &"osu_files".to_string())
+    }
+
+    #[tokio::test]
+    async fn built_in_algorithms_can_not_be_unregistered() {
+        let registry = registry();
+        for name in [PEACE_PERFORMANCE, RELAX, AUTOPILOT].iter() {
+            assert!(registry.unregister(name).await.is_none());
+            assert!(registry.get(Some(name)).await.is_some());
+        }
+
+        registry.register(Arc::new(Plugin("plugin"))).await;
+        assert!(registry.unregister("plugin").await.is_some());
+        assert!(registry.get(Some("plugin")).await.is_none());
+    }
+}
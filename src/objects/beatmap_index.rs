@@ -0,0 +1,391 @@
+use {
+    chrono::Local,
+    ntex::web::types::Data,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    },
+    tokio::sync::{Mutex, RwLock},
+};
+
+use crate::objects::osu_files::{self, OsuFileMetadata};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub bid: Option<i32>,
+    pub sid: Option<i32>,
+    pub file_name: Option<String>,
+    /// When the bid and sid + file name of this file were last confirmed upstream (timestamp),
+    /// 0 means they came from a local file only and never expire
+    #[serde(default)]
+    pub checked_at: i64,
+    /// Mappings given by an admin, never re-checked upstream or replaced by other files
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+#[derive(Default)]
+pub struct IndexData {
+    /// md5 -> entry, this is what we persist
+    pub entries: HashMap<String, IndexEntry>,
+    pub by_bid: HashMap<i32, String>,
+    pub by_sid_file: HashMap<String, String>,
+}
+
+impl IndexData {
+    /// `target` is another file pinned by an admin
+    #[inline(always)]
+    fn is_pinned(&self, target: Option<&String>, md5: &String) -> bool {
+        target.map_or(false, |t| {
+            t != md5 && self.entries.get(t).map_or(false, |e| e.pinned)
+        })
+    }
+
+    #[inline(always)]
+    fn link(&mut self, md5: &String, entry: &IndexEntry) {
+        if let Some(bid) = entry.bid {
+            if entry.pinned || !self.is_pinned(self.by_bid.get(&bid), md5) {
+                self.by_bid.insert(bid, md5.clone());
+            };
+        };
+        if let (Some(sid), Some(file_name)) = (entry.sid, entry.file_name.as_ref()) {
+            let key = sid_file_key(sid, file_name);
+            if entry.pinned || !self.is_pinned(self.by_sid_file.get(&key), md5) {
+                self.by_sid_file.insert(key, md5.clone());
+            };
+        };
+    }
+}
+
+#[inline(always)]
+pub fn sid_file_key(sid: i32, file_name: &String) -> String {
+    format!("{}/{}", sid, osu_files::normalize_file_name(file_name))
+}
+
+/// Persistent mapping of bid and sid + file name to the md5 of the .osu file,
+/// so those requests can use the md5 keyed caches too.
+/// A beatmap may be updated upstream, so mappings learned from osu!api or downloads
+/// older than `ttl` are stale and should be re-checked.
+pub struct BeatmapIndex {
+    pub path: String,
+    pub data: RwLock<IndexData>,
+    /// Seconds, 0 means mappings never expire
+    pub ttl: i64,
+    /// Changed since the last save
+    dirty: AtomicBool,
+    save_lock: Mutex<()>,
+}
+
+impl BeatmapIndex {
+    pub fn load(path: &String, ttl: u64) -> Self {
+        let mut data = IndexData::default();
+        match std::fs::read(path) {
+            Ok(bytes) => match serde_json::from_slice::<HashMap<String, IndexEntry>>(&bytes) {
+                Ok(entries) => {
+                    // Pinned entries last, so they win over other files
+                    for (md5, entry) in entries
+                        .iter()
+                        .filter(|(_, e)| !e.pinned)
+                        .chain(entries.iter().filter(|(_, e)| e.pinned))
+                    {
+                        data.link(md5, entry);
+                    }
+                    data.entries = entries;
+                }
+                Err(err) => warn!(
+                    "[beatmap_index] Failed to parse index file '{}', start with an empty one; err: {:?}",
+                    path, err
+                ),
+            },
+            Err(_) => info!(
+                "[beatmap_index] Index file '{}' not exists, start with an empty one.",
+                path
+            ),
+        };
+        Self {
+            path: path.clone(),
+            data: RwLock::new(data),
+            ttl: ttl as i64,
+            dirty: AtomicBool::new(false),
+            save_lock: Mutex::new(()),
+        }
+    }
+
+    /// Save the index every `interval` seconds if it changed,
+    /// instead of rewriting the whole file after every single .osu file
+    pub fn start_writer(index: Data<BeatmapIndex>, interval: u64) {
+        let duration = Duration::from_secs(interval.max(1));
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(duration).await;
+                if index.is_dirty() {
+                    index.save().await;
+                };
+            }
+        });
+    }
+
+    #[inline(always)]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    #[inline(always)]
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Write to a temp file first, then rename it into place.
+    /// Saves are serialized, so they never write the same temp file at once.
+    pub async fn save(&self) -> bool {
+        let _guard = self.save_lock.lock().await;
+        self.dirty.store(false, Ordering::SeqCst);
+        let json = match serde_json::to_vec(&self.data.read().await.entries) {
+            Ok(j) => j,
+            Err(err) => {
+                warn!("[beatmap_index] Failed to serialize index, err: {:?}", err);
+                self.mark_dirty();
+                return false;
+            }
+        };
+        let tmp_path = format!("{}.tmp", self.path);
+        if let Err(err) = tokio::fs::write(&tmp_path, json).await {
+            warn!(
+                "[beatmap_index] Failed to write index file '{}', err: {:?}",
+                tmp_path, err
+            );
+            self.mark_dirty();
+            return false;
+        };
+        if let Err(err) = tokio::fs::rename(&tmp_path, &self.path).await {
+            warn!(
+                "[beatmap_index] Failed to move index file into '{}', err: {:?}",
+                self.path, err
+            );
+            self.mark_dirty();
+            return false;
+        };
+        true
+    }
+
+    #[inline(always)]
+    fn is_fresh(&self, entry: &IndexEntry) -> bool {
+        self.ttl <= 0
+            || entry.pinned
+            || entry.checked_at == 0
+            || Local::now().timestamp() - entry.checked_at < self.ttl
+    }
+
+    #[inline(always)]
+    fn get_checked(
+        &self,
+        data: &IndexData,
+        md5: Option<&String>,
+        allow_stale: bool,
+    ) -> Option<String> {
+        let md5 = md5?;
+        if allow_stale || data.entries.get(md5).map_or(false, |e| self.is_fresh(e)) {
+            Some(md5.clone())
+        } else {
+            None
+        }
+    }
+
+    #[inline(always)]
+    pub async fn contains(&self, md5: &String) -> bool {
+        self.data.read().await.entries.contains_key(md5)
+    }
+
+    /// Already indexed, and the file's own metadata would change nothing
+    #[inline(always)]
+    pub async fn contains_metadata(&self, md5: &String, metadata: &OsuFileMetadata) -> bool {
+        self.data.read().await.entries.get(md5).map_or(false, |e| {
+            e.file_name.as_ref() == Some(&metadata.file_name())
+                && metadata.bid.map_or(true, |bid| e.bid == Some(bid))
+                && metadata.sid.map_or(true, |sid| e.sid == Some(sid))
+        })
+    }
+
+    /// With `allow_stale`, also returns mappings that should be re-checked upstream
+    #[inline(always)]
+    pub async fn get_by_bid(&self, bid: i32, allow_stale: bool) -> Option<String> {
+        let data = self.data.read().await;
+        self.get_checked(&data, data.by_bid.get(&bid), allow_stale)
+    }
+
+    #[inline(always)]
+    pub async fn get_by_sid_file(
+        &self,
+        sid: i32,
+        file_name: &String,
+        allow_stale: bool,
+    ) -> Option<String> {
+        let data = self.data.read().await;
+        self.get_checked(
+            &data,
+            data.by_sid_file.get(&sid_file_key(sid, file_name)),
+            allow_stale,
+        )
+    }
+
+    /// The bid and sid + file name of this file were just confirmed upstream
+    pub async fn mark_checked(&self, md5: &String) {
+        if let Some(entry) = self.data.write().await.entries.get_mut(md5) {
+            entry.checked_at = Local::now().timestamp();
+            self.mark_dirty();
+        };
+    }
+
+    /// Add or update an entry, known values are not overwritten with `None`.
+    /// Returns true if anything changed.
+    pub async fn insert(&self, md5: &String, entry: IndexEntry) -> bool {
+        let mut data = self.data.write().await;
+        let merged = match data.entries.get(md5) {
+            Some(old) => IndexEntry {
+                bid: entry.bid.or(old.bid),
+                sid: entry.sid.or(old.sid),
+                file_name: entry.file_name.or_else(|| old.file_name.clone()),
+                checked_at: entry.checked_at.max(old.checked_at),
+                pinned: entry.pinned || old.pinned,
+            },
+            None => entry,
+        };
+        if let Some(old) = data.entries.get(md5) {
+            if old.bid == merged.bid
+                && old.sid == merged.sid
+                && old.file_name == merged.file_name
+                && old.checked_at == merged.checked_at
+                && old.pinned == merged.pinned
+            {
+                return false;
+            }
+        };
+        data.link(md5, &merged);
+        data.entries.insert(md5.clone(), merged);
+        self.mark_dirty();
+        true
+    }
+
+    /// Index a .osu file with its metadata, plus anything else we know about it
+    #[inline(always)]
+    pub async fn insert_metadata(
+        &self,
+        md5: &String,
+        metadata: &OsuFileMetadata,
+        bid: Option<i32>,
+        sid: Option<i32>,
+    ) -> bool {
+        self.insert(
+            md5,
+            IndexEntry {
+                bid: bid.or(metadata.bid),
+                sid: sid.or(metadata.sid),
+                file_name: Some(metadata.file_name()),
+                checked_at: 0,
+                pinned: false,
+            },
+        )
+        .await
+    }
+
+    /// The bid and sid + file name of this file were given by an admin
+    pub async fn pin(&self, md5: &String) {
+        if let Some(entry) = self.data.read().await.entries.get(md5) {
+            if entry.pinned {
+                return;
+            };
+        };
+        self.insert(
+            md5,
+            IndexEntry {
+                pinned: true,
+                ..Default::default()
+            },
+        )
+        .await;
+    }
+
+    pub async fn remove(&self, md5: &String) -> Option<IndexEntry> {
+        let mut data = self.data.write().await;
+        let entry = data.entries.remove(md5)?;
+        self.mark_dirty();
+        if let Some(bid) = entry.bid {
+            if data.by_bid.get(&bid) == Some(md5) {
+                data.by_bid.remove(&bid);
+            }
+        };
+        if let (Some(sid), Some(file_name)) = (entry.sid, entry.file_name.as_ref()) {
+            let key = sid_file_key(sid, file_name);
+            if data.by_sid_file.get(&key) == Some(md5) {
+                data.by_sid_file.remove(&key);
+            }
+        };
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_upstream_mappings_expire() {
+        let dir = crate::objects::test_utils::temp_dir("beatmap_index");
+        let index = BeatmapIndex::load(&format!("{}/index.json", dir), 60);
+        let entry = |bid| IndexEntry {
+            bid: Some(bid),
+            sid: Some(1),
+            file_name: Some(format!("{}.osu", bid)),
+            checked_at: 0,
+            pinned: false,
+        };
+        let (local, checked, expired) = ("a".repeat(32), "b".repeat(32), "c".repeat(32));
+        index.insert(&local, entry(1)).await;
+        index.insert(&checked, entry(2)).await;
+        index.mark_checked(&checked).await;
+        index
+            .insert(
+                &expired,
+                IndexEntry {
+                    checked_at: Local::now().timestamp() - 120,
+                    ..entry(3)
+                },
+            )
+            .await;
+
+        assert_eq!(index.get_by_bid(1, false).await, Some(local));
+        assert_eq!(index.get_by_bid(2, false).await, Some(checked));
+        assert_eq!(index.get_by_bid(3, false).await, None);
+        assert_eq!(index.get_by_bid(3, true).await, Some(expired));
+    }
+
+    #[tokio::test]
+    async fn pinned_mappings_are_not_replaced() {
+        let dir = crate::objects::test_utils::temp_dir("beatmap_index_pin");
+        let path = format!("{}/index.json", dir);
+        let index = BeatmapIndex::load(&path, 60);
+        let entry = IndexEntry {
+            bid: Some(1),
+            sid: Some(1),
+            file_name: Some("1.osu".to_string()),
+            checked_at: 0,
+            pinned: false,
+        };
+        let (uploaded, official) = ("a".repeat(32), "b".repeat(32));
+        index.insert(&uploaded, entry.clone()).await;
+        index.pin(&uploaded).await;
+        index.insert(&official, entry.clone()).await;
+        index.mark_checked(&official).await;
+        assert_eq!(index.get_by_bid(1, false).await, Some(uploaded.clone()));
+        assert_eq!(
+            index.get_by_sid_file(1, &"1.osu".to_string(), false).await,
+            Some(uploaded.clone())
+        );
+
+        assert!(index.save().await);
+        let loaded = BeatmapIndex::load(&path, 60);
+        assert_eq!(loaded.get_by_bid(1, false).await, Some(uploaded));
+    }
+}
@@ -9,6 +9,7 @@ use {
 use peace_objects::beatmaps::CommonBeatmapCaches;
 use peace_performance::Beatmap as PPbeatmap;
 
+use crate::objects::calculator::GetBeatmapError;
 use crate::settings::model::LocalConfigData;
 
 #[derive(Clone)]
@@ -32,9 +33,24 @@ impl PPbeatmapCache {
     }
 }
 
+#[derive(Clone)]
+pub struct NegativeCache {
+    pub error: GetBeatmapError,
+    pub time: DateTime<Local>,
+    pub ttl: i64,
+}
+
+impl NegativeCache {
+    #[inline(always)]
+    pub fn remaining(&self) -> i64 {
+        self.ttl - (Local::now().timestamp() - self.time.timestamp())
+    }
+}
+
 pub struct Caches {
     pub beatmap_cache: CommonBeatmapCaches,
     pub pp_beatmap_cache: RwLock<HashMap<String, PPbeatmapCache>>,
+    pub negative_cache: RwLock<HashMap<String, NegativeCache>>,
     pub config: LocalConfigData,
 }
 
@@ -48,6 +64,7 @@ impl Caches {
                 length: AtomicI32::new(0),
             },
             pp_beatmap_cache: RwLock::new(HashMap::with_capacity(200)),
+            negative_cache: RwLock::new(HashMap::with_capacity(200)),
             config,
         }
     }
@@ -61,4 +78,88 @@ impl Caches {
         };
         cw.insert(md5, pp_beatmap_cache);
     }
+
+    #[inline(always)]
+    pub async fn cache_negative(&self, key: String, error: GetBeatmapError) {
+        let cfg = &self.config.negative_cache;
+        let ttl = match error {
+            GetBeatmapError::NotFound => cfg.not_found_ttl,
+            GetBeatmapError::ParseError => cfg.parse_error_ttl,
+            // Upstream outage or local errors, not the beatmap's fault
+            _ => return,
+        };
+        if !cfg.enabled || ttl == 0 {
+            return;
+        };
+        let mut cw = self.negative_cache.write().await;
+        // Full: make room for the new entry, expired entries first, then the oldest one
+        if cw.len() >= cfg.max && !cw.contains_key(&key) {
+            cw.retain(|_, c| c.remaining() > 0);
+            if cw.len() >= cfg.max {
+                let oldest = cw
+                    .iter()
+                    .min_by_key(|(_, c)| c.time)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    debug!(
+                        "[negative_cache] Cache exceed max limit, evict '{}'.",
+                        oldest
+                    );
+                    cw.remove(&oldest);
+                };
+            };
+        };
+        cw.insert(
+            key,
+            NegativeCache {
+                error,
+                time: Local::now(),
+                ttl: ttl as i64,
+            },
+        );
+    }
+
+    /// Returns the cached error and its remaining ttl (seconds), if not expired
+    #[inline(always)]
+    pub async fn get_negative(&self, key: &String) -> Option<(GetBeatmapError, i64)> {
+        if !self.config.negative_cache.enabled {
+            return None;
+        };
+        let c = self.negative_cache.read().await.get(key).cloned()?;
+        let remaining = c.remaining();
+        if remaining > 0 {
+            Some((c.error, remaining))
+        } else {
+            self.negative_cache.write().await.remove(key);
+            None
+        }
+    }
+
+    /// A .osu file was added or changed: forget the failures we remembered for it,
+    /// and the bid keyed entry, which may point to an older version of the beatmap
+    pub async fn invalidate_beatmap(&self, md5: &String, bid: Option<i32>, sid: Option<i32>) {
+        {
+            let mut negative = self.negative_cache.write().await;
+            negative.remove(md5);
+            if let Some(bid) = bid {
+                negative.remove(&format!("bid_{}", bid));
+            };
+            if let Some(sid) = sid {
+                let prefix = format!("sid_{}/", sid);
+                negative.retain(|k, _| !k.starts_with(&prefix));
+            };
+        }
+        if let Some(bid) = bid {
+            self.pp_beatmap_cache
+                .write()
+                .await
+                .remove(&format!("bid_{}", bid));
+        };
+    }
+
+    /// A .osu file was removed or replaced, its md5 is not valid anymore
+    #[inline(always)]
+    pub async fn remove_pp_beatmap(&self, md5: &String) {
+        self.pp_beatmap_cache.write().await.remove(md5);
+    }
 }
@@ -1,17 +1,25 @@
-use crate::objects::caches::{Caches, PPbeatmapCache};
+use crate::objects::{
+    algorithm::{AlgorithmRegistry, PPAlgorithm},
+    caches::{Caches, PPbeatmapCache},
+    osu_api_v2::OsuApiV2Error,
+    osu_files, osu_strains,
+};
 use crate::Glob;
 
 use {
     bytes::Bytes,
     ntex::web::types::Data,
-    serde::Deserialize,
+    serde::{Deserialize, Serialize},
     serde_json::{json, Value},
-    std::{cmp::PartialEq, time::Instant},
+    std::{cmp::PartialEq, collections::HashMap, sync::Arc, time::Instant},
     tokio::fs::File,
 };
 
 use peace_objects::beatmaps::traits::{BeatmapCacheStorage, MyBeatmapCache};
-use peace_performance::{AnyPP, Beatmap as PPbeatmap, FruitsPP, ManiaPP, OsuPP, PpResult, TaikoPP};
+use peace_performance::{
+    AnyPP, Beatmap as PPbeatmap, BeatmapExt, FruitsPP, GameMode, HitObjectKind, ManiaPP, OsuPP,
+    PpResult, TaikoPP,
+};
 
 macro_rules! set_calculator {
     ($target:ident.$attr:ident, $calculator:ident) => {
@@ -28,10 +36,14 @@ macro_rules! set_calculator {
     };
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum GetBeatmapError {
     FileNotFound,
     ParseError,
+    NotFound,
+    Unavailable,
+    RateLimited,
+    Offline,
 }
 
 impl GetBeatmapError {
@@ -40,6 +52,10 @@ impl GetBeatmapError {
         match self {
             Self::FileNotFound => "cannot find .osu file",
             Self::ParseError => "cannot parse .osu file",
+            Self::NotFound => "cannot found beatmap",
+            Self::Unavailable => "beatmap sources are unavailable",
+            Self::RateLimited => "osu!api rate limit exceeded, try again later",
+            Self::Offline => "beatmap not available offline",
         }
     }
 
@@ -48,8 +64,123 @@ impl GetBeatmapError {
         match self {
             Self::FileNotFound => -1,
             Self::ParseError => -2,
+            Self::NotFound => -3,
+            Self::Unavailable => -4,
+            Self::RateLimited => -5,
+            Self::Offline => -6,
+        }
+    }
+
+    #[inline(always)]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::FileNotFound => "file_not_found",
+            Self::ParseError => "parse_error",
+            Self::NotFound => "not_found",
+            Self::Unavailable => "unavailable",
+            Self::RateLimited => "rate_limited",
+            Self::Offline => "offline",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GetBeatmapFailed {
+    pub error: GetBeatmapError,
+    /// Seconds left, if this failure was answered from the negative cache
+    pub negative_cache_ttl: Option<i64>,
+}
+
+impl GetBeatmapFailed {
+    #[inline(always)]
+    pub fn new(error: GetBeatmapError) -> Self {
+        Self {
+            error,
+            negative_cache_ttl: None,
         }
     }
+
+    #[inline(always)]
+    pub fn json(&self) -> Value {
+        json!({
+            "status": self.error.error_status(),
+            "message": self.error.error_message(),
+            "pp": null,
+            "negative_cache": self.negative_cache_ttl.map(|ttl| json!({
+                "kind": self.error.kind(),
+                "ttl": ttl,
+            })),
+        })
+    }
+}
+
+/// Hit counts that cannot happen on the beatmap
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvalidHitCounts {
+    /// More passed objects than the map has
+    PassedObjects { passed: usize, max: usize },
+    /// More judgements than (passed) objects
+    TooManyHits { hits: usize, max: usize },
+    /// Judgement that does not exist in the mode (e.g. n50 in osu!taiko)
+    UnexpectedHits { field: &'static str, count: usize },
+    /// Combo over the map's max combo
+    ComboTooHigh { combo: usize, max: usize },
+    /// Accuracy out of 0 ~ 100
+    Accuracy(f32),
+}
+
+impl InvalidHitCounts {
+    #[inline(always)]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::PassedObjects { .. } => "passed_objects",
+            Self::TooManyHits { .. } => "too_many_hits",
+            Self::UnexpectedHits { .. } => "unexpected_hits",
+            Self::ComboTooHigh { .. } => "combo_too_high",
+            Self::Accuracy(_) => "accuracy",
+        }
+    }
+
+    pub fn error_message(&self) -> String {
+        match self {
+            Self::PassedObjects { passed, max } => {
+                format!("passed_obj {} is over the object count {}", passed, max)
+            }
+            Self::TooManyHits { hits, max } => {
+                format!(
+                    "{} judgements is over the (passed) object count {}",
+                    hits, max
+                )
+            }
+            Self::UnexpectedHits { field, count } => {
+                format!("{} {} is not possible in this mode", field, count)
+            }
+            Self::ComboTooHigh { combo, max } => {
+                format!("combo {} is over the max combo {}", combo, max)
+            }
+            Self::Accuracy(acc) => format!("acc {} is not in 0 ~ 100", acc),
+        }
+    }
+
+    #[inline(always)]
+    pub fn json(&self) -> Value {
+        let detail = match self {
+            Self::PassedObjects { passed, max } => json!({ "value": passed, "max": max }),
+            Self::TooManyHits { hits, max } => json!({ "value": hits, "max": max }),
+            Self::UnexpectedHits { field, count } => json!({ "field": field, "value": count }),
+            Self::ComboTooHigh { combo, max } => json!({ "value": combo, "max": max }),
+            Self::Accuracy(acc) => json!({ "value": acc, "max": 100 }),
+        };
+        json!({
+            "status": -7,
+            "message": self.error_message(),
+            "pp": null,
+            "invalid": {
+                "kind": self.kind(),
+                "detail": detail,
+            },
+        })
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -60,10 +191,23 @@ pub struct CalcData {
     pub file_name: Option<String>,
     pub mode: Option<u8>,
     pub mods: Option<u32>,
+    /// osu!ctb: tiny droplets
     pub n50: Option<usize>,
+    /// osu!ctb: droplets
     pub n100: Option<usize>,
+    /// osu!ctb: fruits
     pub n300: Option<usize>,
+    /// osu!ctb: missed tiny droplets, osu!mania: 200s,
+    /// osu!taiko: 100s on big notes (already in n100, ignored)
     pub katu: Option<usize>,
+    /// osu!mania: MAX (300g), osu!std / osu!taiko: ignored
+    pub geki: Option<usize>,
+    /// osu!mania: 200s, same as katu
+    pub n200: Option<usize>,
+    /// osu!ctb: missed droplets, they break combo like missed fruits
+    pub droplet_miss: Option<usize>,
+    /// osu!ctb: missed tiny droplets, same as katu
+    pub tiny_droplet_miss: Option<usize>,
     pub acc: Option<f32>,
     pub passed_obj: Option<usize>,
     pub combo: Option<usize>,
@@ -72,6 +216,333 @@ pub struct CalcData {
     pub simple: Option<i32>,
     pub acc_list: Option<i32>,
     pub no_miss: Option<i32>,
+    /// Gradual: calculate every `stride` objects
+    pub stride: Option<usize>,
+    /// Gradual: calculate every `time_step` milliseconds
+    pub time_step: Option<f32>,
+    /// Mods table: comma separated mod combinations (numbers or names like "HDDT")
+    pub mods_list: Option<String>,
+    /// Mods table: comma separated accuracies
+    pub accs: Option<String>,
+    /// Clock rate, replaces the DT / HT / NC mods
+    pub rate: Option<f32>,
+    /// Mods of the request, when `mods` were changed to calculate a map with `rate` applied
+    #[serde(skip)]
+    pub requested_mods: Option<u32>,
+    /// How hit counts are simulated from `acc`
+    pub strategy: Option<HitStrategy>,
+    /// pp algorithm name
+    pub algo: Option<String>,
+    /// Also calculate with these algorithms (comma separated, or "all")
+    pub compare: Option<String>,
+    /// Scoring model of the play, by default v2 with the SV2 mod,
+    /// lazer with lazer judgements, else v1
+    pub scoring: Option<ScoringModel>,
+    /// lazer: slider tails hit (osu!std)
+    pub slider_tail_hit: Option<usize>,
+    /// lazer: slider ticks and repeats hit (osu!std)
+    pub large_tick_hit: Option<usize>,
+    /// lazer: slider ticks and repeats missed (osu!std)
+    pub large_tick_miss: Option<usize>,
+}
+
+/// ScoreV2 mod
+pub const SCORE_V2_MOD: u32 = 1 << 29;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoringModel {
+    /// osu!stable ScoreV1
+    V1,
+    /// osu!stable ScoreV2, accuracy is calculated as in v1, but MAX is worth 305 in osu!mania
+    V2,
+    /// osu!lazer, slider tails and ticks count for accuracy
+    Lazer,
+}
+
+impl ScoringModel {
+    #[inline(always)]
+    pub fn of(data: &CalcData) -> Self {
+        if let Some(scoring) = data.scoring {
+            return scoring;
+        };
+        if data.mods.unwrap_or(0) & SCORE_V2_MOD > 0 {
+            Self::V2
+        } else if data.slider_tail_hit.is_some()
+            || data.large_tick_hit.is_some()
+            || data.large_tick_miss.is_some()
+        {
+            Self::Lazer
+        } else {
+            Self::V1
+        }
+    }
+}
+
+/// How the non-300 judgements of a simulated play are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HitStrategy {
+    /// As many 100s as needed, 50s only if 100s are not enough
+    #[serde(rename = "prefer_100")]
+    Prefer100,
+    /// 50s first, fewer non-300 judgements
+    #[serde(rename = "prefer_50")]
+    Prefer50,
+    /// Same count of 100s and 50s
+    Even,
+}
+
+impl Default for HitStrategy {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::Prefer100
+    }
+}
+
+/// Mode of the calculation, 4 (or none) means the map's own mode
+#[inline(always)]
+pub fn calc_mode(beatmap: &PPbeatmap, data: &CalcData) -> u8 {
+    match data.mode {
+        Some(mode) if mode < 4 => mode,
+        _ => beatmap.mode as u8,
+    }
+}
+
+/// Check hit counts against the map's object count and `passed_obj`.
+///
+/// osu!ctb judgements depend on the fruits / droplets of the map,
+/// so only its combo is checked (see `validate_combo`).
+pub fn validate_hit_counts(beatmap: &PPbeatmap, data: &CalcData) -> Result<(), InvalidHitCounts> {
+    if let Some(acc) = data.acc {
+        if !(0.0..=100.0).contains(&acc) {
+            return Err(InvalidHitCounts::Accuracy(acc));
+        }
+    };
+    let objects = beatmap.hit_objects.len();
+    if let Some(passed) = data.passed_obj {
+        if passed > objects {
+            return Err(InvalidHitCounts::PassedObjects {
+                passed,
+                max: objects,
+            });
+        }
+    };
+
+    let mode = calc_mode(beatmap, data);
+    let (n300, n100, n50, katu, miss) = (
+        data.n300.unwrap_or(0),
+        data.n100.unwrap_or(0),
+        data.n50.unwrap_or(0),
+        data.katu.unwrap_or(0),
+        data.miss.unwrap_or(0),
+    );
+    let (hits, max) = match mode {
+        0 => (n300 + n100 + n50 + miss, objects),
+        1 => {
+            if n50 > 0 {
+                return Err(InvalidHitCounts::UnexpectedHits {
+                    field: "n50",
+                    count: n50,
+                });
+            };
+            (n300 + n100 + miss, beatmap.n_circles as usize)
+        }
+        // katu is n200
+        3 => (
+            data.geki.unwrap_or(0) + n300 + katu + n100 + n50 + miss,
+            objects,
+        ),
+        _ => return Ok(()),
+    };
+    let max = data.passed_obj.map_or(max, |passed| passed.min(max));
+    if hits > max {
+        return Err(InvalidHitCounts::TooManyHits { hits, max });
+    };
+    if let Some(tails) = data.slider_tail_hit {
+        let sliders = passed_sliders(beatmap, data);
+        if mode != 0 {
+            return Err(InvalidHitCounts::UnexpectedHits {
+                field: "slider_tail_hit",
+                count: tails,
+            });
+        } else if tails > sliders {
+            return Err(InvalidHitCounts::TooManyHits {
+                hits: tails,
+                max: sliders,
+            });
+        };
+    };
+    Ok(())
+}
+
+/// Sliders in the passed part of the map
+#[inline(always)]
+pub fn passed_sliders(beatmap: &PPbeatmap, data: &CalcData) -> usize {
+    beatmap
+        .hit_objects
+        .iter()
+        .take(data.passed_obj.unwrap_or(usize::MAX))
+        .filter(|h| matches!(h.kind, HitObjectKind::Slider { .. }))
+        .count()
+}
+
+/// osu!lazer osu!std accuracy: judgements weighted by their base score,
+/// slider tails (150) and large ticks (30) included. Other modes are as in v1.
+pub fn lazer_accuracy(beatmap: &PPbeatmap, data: &CalcData) -> Option<f32> {
+    if calc_mode(beatmap, data) != 0 {
+        return hit_counts_accuracy(beatmap, data);
+    };
+    let (n300, n100, n50, miss) = (
+        data.n300.unwrap_or(0) as f32,
+        data.n100.unwrap_or(0) as f32,
+        data.n50.unwrap_or(0) as f32,
+        data.miss.unwrap_or(0) as f32,
+    );
+    let tails = data.slider_tail_hit.unwrap_or(0) as f32;
+    let (ticks, tick_misses) = (
+        data.large_tick_hit.unwrap_or(0) as f32,
+        data.large_tick_miss.unwrap_or(0) as f32,
+    );
+    // Tails are only counted when given
+    let max_tails = if data.slider_tail_hit.is_some() {
+        passed_sliders(beatmap, data) as f32
+    } else {
+        0.0
+    };
+    let hit = n300 * 300.0 + n100 * 100.0 + n50 * 50.0 + tails * 150.0 + ticks * 30.0;
+    let total =
+        (n300 + n100 + n50 + miss) * 300.0 + max_tails * 150.0 + (ticks + tick_misses) * 30.0;
+    if total > 0.0 {
+        Some(hit / total * 100.0)
+    } else {
+        None
+    }
+}
+
+/// Accuracy (0 ~ 100) of the play with its scoring model, None if there are no hit counts
+#[inline(always)]
+pub fn play_accuracy(beatmap: &PPbeatmap, data: &CalcData) -> Option<f32> {
+    match ScoringModel::of(data) {
+        ScoringModel::Lazer => lazer_accuracy(beatmap, data),
+        _ => hit_counts_accuracy(beatmap, data),
+    }
+}
+
+/// Combo can not be over the max combo of the (passed part of the) map
+#[inline(always)]
+pub fn validate_combo(data: &CalcData, result: &PpResult) -> Result<(), InvalidHitCounts> {
+    match (data.combo, result.attributes.max_combo()) {
+        (Some(combo), Some(max)) if combo > max => {
+            Err(InvalidHitCounts::ComboTooHigh { combo, max })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Map the full judgement set of each mode into what the calculators take:
+/// osu!ctb droplet misses are misses, tiny droplet misses are katu;
+/// osu!mania 200s are katu, and the accuracy (and ScoreV1 score, if not given)
+/// are derived from the judgements.
+pub fn normalize_judgements(beatmap: &PPbeatmap, data: CalcData) -> CalcData {
+    match calc_mode(beatmap, &data) {
+        2 => CalcData {
+            katu: data.tiny_droplet_miss.or(data.katu),
+            miss: match (data.miss, data.droplet_miss) {
+                (None, None) => None,
+                (miss, droplet_miss) => Some(miss.unwrap_or(0) + droplet_miss.unwrap_or(0)),
+            },
+            ..data
+        },
+        3 => {
+            let mut data = CalcData {
+                katu: data.n200.or(data.katu),
+                ..data
+            };
+            if has_mania_judgements(&data) {
+                let acc = hit_counts_accuracy(beatmap, &data);
+                if data.acc.is_none() {
+                    data.acc = acc;
+                };
+                if data.score.is_none() {
+                    data.score = mania_score_v1(&data);
+                };
+            };
+            data
+        }
+        _ => data,
+    }
+}
+
+/// osu!mania judgements are given (katu is n200)
+#[inline(always)]
+pub fn has_mania_judgements(data: &CalcData) -> bool {
+    data.geki.is_some()
+        || data.n300.is_some()
+        || data.katu.is_some()
+        || data.n100.is_some()
+        || data.n50.is_some()
+}
+
+/// ScoreV1 of an osu!mania play from its judgements, without the combo bonus
+/// (it follows the hit values), scaled by the EZ / NF / HT multiplier
+pub fn mania_score_v1(data: &CalcData) -> Option<u32> {
+    let (geki, n300, n200, n100, n50, miss) = (
+        data.geki.unwrap_or(0) as f64,
+        data.n300.unwrap_or(0) as f64,
+        data.katu.unwrap_or(0) as f64,
+        data.n100.unwrap_or(0) as f64,
+        data.n50.unwrap_or(0) as f64,
+        data.miss.unwrap_or(0) as f64,
+    );
+    let total = geki + n300 + n200 + n100 + n50 + miss;
+    if total == 0.0 {
+        return None;
+    };
+    let hit = geki * 320.0 + n300 * 300.0 + n200 * 200.0 + n100 * 100.0 + n50 * 50.0;
+    let mods = data.mods.unwrap_or(0);
+    // EZ, NF, HT halve the score each
+    let halved = [1u32, 2, 256].iter().filter(|m| mods & **m > 0).count();
+    let multiplier = 0.5f64.powi(halved as i32);
+    Some((1_000_000.0 * hit / (total * 320.0) * multiplier).round() as u32)
+}
+
+/// Accuracy (0 ~ 100) derived from the hit counts, None if there are no hit counts
+pub fn hit_counts_accuracy(beatmap: &PPbeatmap, data: &CalcData) -> Option<f32> {
+    let (n300, n100, n50, katu, miss) = (
+        data.n300.unwrap_or(0) as f32,
+        data.n100.unwrap_or(0) as f32,
+        data.n50.unwrap_or(0) as f32,
+        data.katu.unwrap_or(0) as f32,
+        data.miss.unwrap_or(0) as f32,
+    );
+    let (hit, total) = match calc_mode(beatmap, data) {
+        0 => (
+            n300 * 300.0 + n100 * 100.0 + n50 * 50.0,
+            (n300 + n100 + n50 + miss) * 300.0,
+        ),
+        1 => (n300 + n100 * 0.5, n300 + n100 + miss),
+        // katu is missed tiny droplets
+        2 => (n300 + n100 + n50, n300 + n100 + n50 + katu + miss),
+        // katu is n200
+        _ => {
+            let geki = data.geki.unwrap_or(0) as f32;
+            let max = if ScoringModel::of(data) == ScoringModel::V2 {
+                305.0
+            } else {
+                300.0
+            };
+            (
+                geki * max + n300 * 300.0 + katu * 200.0 + n100 * 100.0 + n50 * 50.0,
+                (geki + n300 + katu + n100 + n50 + miss) * max,
+            )
+        }
+    };
+    if total > 0.0 {
+        Some(hit / total * 100.0)
+    } else {
+        None
+    }
 }
 
 #[inline(always)]
@@ -136,6 +607,655 @@ pub async fn calculate_acc_list(beatmap: &PPbeatmap, data: &CalcData) -> Value {
     })
 }
 
+/// Hit counts for a play that only has `acc` (and `miss`), so the breakdown
+/// the pp is calculated with is known. Accuracy is in 50 units (300 = 6, 100 = 2, 50 = 1)
+/// for osu!std, and in 100 units (300 = 2, 100 = 1) for osu!taiko.
+///
+/// osu!ctb depends on the droplets of the map and osu!mania pp is calculated by score,
+/// so they are not simulated.
+pub fn simulate_hit_counts(beatmap: &PPbeatmap, data: &CalcData) -> Option<CalcData> {
+    let acc = data.acc?;
+    if data.n300.is_some() || data.n100.is_some() || data.n50.is_some() || data.katu.is_some() {
+        return None;
+    };
+    let mode = calc_mode(beatmap, data);
+    let objects = match mode {
+        0 => beatmap.hit_objects.len(),
+        1 => beatmap.n_circles as usize,
+        _ => return None,
+    };
+    let total = data
+        .passed_obj
+        .map_or(objects, |passed| passed.min(objects));
+    let miss = data.miss.unwrap_or(0).min(total);
+    let remaining = total - miss;
+    let round = |x: f64| x.round().max(0.0) as usize;
+
+    let (n100, n50) = if mode == 0 {
+        // Points lost from all 300s: 100 loses 4, 50 loses 5
+        let target = round(acc as f64 / 100.0 * 6.0 * total as f64);
+        let deficit = (6 * remaining).saturating_sub(target);
+        match data.strategy.unwrap_or_default() {
+            HitStrategy::Prefer100 => {
+                if deficit > 4 * remaining {
+                    // 100s are not enough, turn some of them into 50s
+                    let n50 = (deficit - 4 * remaining).min(remaining);
+                    (remaining - n50, n50)
+                } else {
+                    (round(deficit as f64 / 4.0).min(remaining), 0)
+                }
+            }
+            HitStrategy::Prefer50 => {
+                let n50 = (deficit / 5).min(remaining);
+                let n100 = round((deficit - n50 * 5) as f64 / 4.0).min(remaining - n50);
+                (n100, n50)
+            }
+            HitStrategy::Even => {
+                let n = round(deficit as f64 / 9.0).min(remaining / 2);
+                (n, n)
+            }
+        }
+    } else {
+        // Only 100s in osu!taiko, each loses 1
+        let target = round(acc as f64 / 100.0 * 2.0 * total as f64);
+        ((2 * remaining).saturating_sub(target).min(remaining), 0)
+    };
+
+    Some(CalcData {
+        n300: Some(remaining - n100 - n50),
+        n100: Some(n100),
+        n50: Some(n50),
+        miss: Some(miss),
+        acc: None,
+        ..data.clone()
+    })
+}
+
+/// Allowed range of `rate`
+pub const MIN_CLOCK_RATE: f32 = 0.5;
+pub const MAX_CLOCK_RATE: f32 = 2.0;
+
+/// Mods that change clock rate (DT, HT, NC)
+pub const CLOCK_RATE_MODS: u32 = 64 | 256 | 512;
+
+/// Clock rate of the DT / HT / NC mods
+#[inline(always)]
+pub fn mods_clock_rate(mods: u32) -> f32 {
+    if mods & (64 | 512) > 0 {
+        1.5
+    } else if mods & 256 > 0 {
+        0.75
+    } else {
+        1.0
+    }
+}
+
+#[inline(always)]
+fn ar_to_preempt(ar: f32) -> f32 {
+    if ar > 5.0 {
+        1200.0 - 150.0 * (ar - 5.0)
+    } else {
+        1800.0 - 120.0 * ar
+    }
+}
+
+#[inline(always)]
+fn preempt_to_ar(preempt: f32) -> f32 {
+    if preempt < 1200.0 {
+        5.0 + (1200.0 - preempt) / 150.0
+    } else {
+        (1800.0 - preempt) / 120.0
+    }
+}
+
+/// peace-performance only knows the clock rates of DT and HT, so for any other rate
+/// we calculate a copy of the map as it is played at that rate, without rate mods:
+/// all times are divided by `rate`, and AR / OD are re-derived from the scaled
+/// approach time and hit window. EZ / HR are applied before scaling, like the game does.
+///
+/// Returns the map and the mods to calculate it with.
+pub fn apply_clock_rate(beatmap: &PPbeatmap, mode: u8, mods: u32, rate: f32) -> (PPbeatmap, u32) {
+    let mut map = beatmap.clone();
+    let scale = |t: f32| t / rate;
+
+    // EZ / HR, then strip them
+    let multiplier = if mods & 16 > 0 {
+        1.4
+    } else if mods & 2 > 0 {
+        0.5
+    } else {
+        1.0
+    };
+    let (mut ar, mut od) = (map.ar, map.od);
+    if mode != 3 {
+        od = (od * multiplier).min(10.0);
+    };
+    if mode == 0 || mode == 2 {
+        ar = (ar * multiplier).min(10.0);
+        let cs_multiplier = if mods & 16 > 0 { 1.3 } else { multiplier };
+        map.cs = (map.cs * cs_multiplier).min(10.0);
+    };
+    map.hp = (map.hp * multiplier).min(10.0);
+
+    // Approach time and hit windows in game time
+    map.ar = preempt_to_ar(ar_to_preempt(ar) / rate);
+    map.od = match mode {
+        // 300 hit window: 80 - 6 * od
+        0 => (80.0 - (80.0 - 6.0 * od) / rate) / 6.0,
+        // great hit window: 50 - 3 * od
+        1 => (50.0 - (50.0 - 3.0 * od) / rate) / 3.0,
+        // perfect hit window: 64 - 3 * od, EZ / HR are still applied by the calculator
+        3 => (64.0 - (64.0 - 3.0 * od) / rate) / 3.0,
+        // osu!ctb don't use it
+        _ => od,
+    };
+
+    for h in map.hit_objects.iter_mut() {
+        h.start_time = scale(h.start_time);
+        match &mut h.kind {
+            HitObjectKind::Spinner { end_time } | HitObjectKind::Hold { end_time } => {
+                *end_time = scale(*end_time)
+            }
+            _ => {}
+        }
+    }
+    for t in map.timing_points.iter_mut() {
+        t.time = scale(t.time);
+        t.beat_len = scale(t.beat_len);
+    }
+    for d in map.difficulty_points.iter_mut() {
+        d.time = scale(d.time);
+    }
+
+    let mods = if mode == 3 {
+        mods & !CLOCK_RATE_MODS
+    } else {
+        mods & !(CLOCK_RATE_MODS | 2 | 16)
+    };
+    (map, mods)
+}
+
+/// Full combo projection of a play: the observed hit ratios (misses excluded)
+/// are extrapolated over the whole map, combo is left to the map's max combo.
+///
+/// osu!std and osu!taiko project the hit counts; osu!ctb accuracy and
+/// osu!mania accuracy and score are derived from the judgements without the misses.
+pub fn if_fc_data(beatmap: &PPbeatmap, data: &CalcData) -> CalcData {
+    let mut fc = CalcData {
+        combo: None,
+        miss: Some(0),
+        passed_obj: None,
+        score: None,
+        ..data.clone()
+    };
+    let mode = calc_mode(beatmap, data);
+    let total = match mode {
+        0 => beatmap.hit_objects.len(),
+        1 => beatmap.n_circles as usize,
+        _ => 0,
+    };
+    let (n300, n100, n50) = (
+        data.n300.unwrap_or(0),
+        data.n100.unwrap_or(0),
+        data.n50.unwrap_or(0),
+    );
+    let hits = n300 + n100 + n50;
+
+    match mode {
+        // Droplet misses are in misses, tiny droplet misses (katu) don't break combo
+        2 => {
+            if let Some(acc) = hit_counts_accuracy(beatmap, &fc) {
+                fc.acc = Some(acc);
+                fc.n300 = None;
+                fc.n100 = None;
+                fc.n50 = None;
+                fc.katu = None;
+            };
+        }
+        // The judgement ratios without misses, over the whole map
+        3 => {
+            if has_mania_judgements(&fc) {
+                fc.acc = hit_counts_accuracy(beatmap, &fc);
+                fc.score = mania_score_v1(&fc);
+            } else {
+                // Nothing to project, the given score is the best guess (None would mean SS)
+                fc.score = data.score;
+            };
+        }
+        _ if total > 0 && hits > 0 => {
+            let ratio = |n: usize| n as f64 / hits as f64 * total as f64;
+            let p100 = ratio(n100).round() as usize;
+            let p50 = if mode == 0 {
+                ratio(n50).round() as usize
+            } else {
+                0
+            };
+            let p300 = total.saturating_sub(p100 + p50);
+            fc.n300 = Some(p300);
+            fc.n100 = Some(p100);
+            fc.n50 = Some(p50);
+            fc.acc = None;
+        }
+        _ if hits > 0 && data.acc.is_none() => {
+            // Only the hit counts of the play are known, keep their ratio as accuracy
+            fc.acc = Some(
+                (n300 as f32 * 300.0 + n100 as f32 * 100.0 + n50 as f32 * 50.0) / hits as f32 / 3.0,
+            );
+            fc.n300 = None;
+            fc.n100 = None;
+            fc.n50 = None;
+        }
+        _ => {}
+    };
+    fc
+}
+
+/// Mods that change difficulty attributes (EZ, HR, DT, HT, FL)
+pub const DIFFICULTY_MODS: u32 = 2 | 16 | 64 | 256 | 1024;
+
+/// Max mod combinations in one mods table
+pub const MAX_MOD_COMBINATIONS: usize = 64;
+
+/// Max accuracies of each mod combination in one mods table
+pub const MAX_MODS_TABLE_ACCS: usize = 10;
+
+/// Default combinations of the mods table
+pub const DEFAULT_MOD_COMBINATIONS: &[u32] = &[
+    0,    // NM
+    8,    // HD
+    16,   // HR
+    64,   // DT
+    24,   // HDHR
+    72,   // HDDT
+    88,   // HDHRDT
+    2,    // EZ
+    10,   // EZHD
+    256,  // HT
+    1024, // FL
+    1032, // HDFL
+    1048, // HDHRFL
+];
+
+const MOD_NAMES: &[(&str, u32)] = &[
+    ("NF", 1),
+    ("EZ", 2),
+    ("TD", 4),
+    ("HD", 8),
+    ("HR", 16),
+    ("SD", 32),
+    ("DT", 64),
+    ("RX", 128),
+    ("HT", 256),
+    ("NC", 512),
+    ("FL", 1024),
+    ("SO", 4096),
+    ("AP", 8192),
+    ("PF", 16384),
+];
+
+/// "HDDT" style name of mods, NC and PF hide the DT and SD they imply
+pub fn mods_name(mods: u32) -> String {
+    let name: String = MOD_NAMES
+        .iter()
+        .filter(|(name, bit)| {
+            mods & bit > 0
+                && !(*name == "DT" && mods & 512 > 0)
+                && !(*name == "SD" && mods & 16384 > 0)
+        })
+        .map(|(name, _)| *name)
+        .collect();
+    if name.is_empty() {
+        "NM".to_string()
+    } else {
+        name
+    }
+}
+
+/// Parse "HDDT" style mods names, or mods numbers
+pub fn parse_mods(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if let Ok(mods) = s.parse::<u32>() {
+        return Some(mods);
+    };
+    let s = s.to_uppercase();
+    if s == "NM" {
+        return Some(0);
+    };
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    };
+    let mut mods = 0;
+    for i in (0..s.len()).step_by(2) {
+        let (_, bit) = MOD_NAMES.iter().find(|(name, _)| *name == &s[i..i + 2])?;
+        mods |= bit;
+    }
+    // NC and PF are DT and SD too
+    if mods & 512 > 0 {
+        mods |= 64;
+    };
+    if mods & 16384 > 0 {
+        mods |= 32;
+    };
+    Some(mods)
+}
+
+/// Stars and max pp (and pp at `accs`) of each mod combination.
+/// Difficulty attributes are calculated once per difficulty changing mods set,
+/// and reused by the other combinations.
+pub async fn calculate_mods_table(
+    beatmap: &PPbeatmap,
+    mode: u8,
+    combinations: &[u32],
+    accs: &[f32],
+) -> Value {
+    let mut attributes = HashMap::new();
+    let mut table = Vec::with_capacity(combinations.len());
+    for &mods in combinations {
+        // NC is calculated as DT
+        let key = (mods | if mods & 512 > 0 { 64 } else { 0 }) & DIFFICULTY_MODS;
+        let c = mode_calculator(mode, beatmap).mods(mods);
+        let mut c = match attributes.get(&key) {
+            Some(attrs) => c.attributes(attrs.clone()),
+            None => c,
+        };
+        let max = c.calculate().await;
+        attributes
+            .entry(key)
+            .or_insert_with(|| max.attributes.clone());
+
+        let mut acc_pp = serde_json::Map::new();
+        for &acc in accs {
+            c.set_accuracy(acc);
+            acc_pp.insert(acc.to_string(), json!(c.calculate().await.pp()));
+        }
+        table.push(json!({
+            "mods": mods,
+            "name": mods_name(mods),
+            "stars": max.attributes.stars(),
+            "max_pp": max.pp(),
+            "acc_pp": acc_pp,
+        }));
+    }
+    Value::Array(table)
+}
+
+/// Strain peak of each section of the map in mode `mode` (converted from osu!std like
+/// `calculate_pp` does), with the time the section ends at, in map time.
+/// `strain` is the calculator's combined strain, osu!std also has its aim and speed peaks.
+pub fn strain_timeline(beatmap: &PPbeatmap, mode: u8, mods: u32) -> Value {
+    let converted;
+    let beatmap = if mode != beatmap.mode as u8 && beatmap.mode == GameMode::STD {
+        let mut map = beatmap.clone();
+        map.mode = match mode {
+            1 => GameMode::TKO,
+            2 => GameMode::CTB,
+            3 => GameMode::MNA,
+            _ => GameMode::STD,
+        };
+        converted = map;
+        &converted
+    } else {
+        beatmap
+    };
+    let strains = beatmap.strains(mods);
+    let section_length = strains.section_length;
+    // Sections are aligned to multiples of the section length
+    let first = beatmap.hit_objects.first().map_or(0.0, |h| h.start_time);
+    let start = (first / section_length).ceil() * section_length;
+    let time = |i: usize| start + i as f32 * section_length;
+    // The highest section, and its time
+    let peak = |values: &[f32]| {
+        values.iter().enumerate().fold(
+            (0, 0.0f32),
+            |max, (i, v)| if *v > max.1 { (i, *v) } else { max },
+        )
+    };
+    let osu = if beatmap.mode == GameMode::STD {
+        Some(osu_strains::osu_strains(beatmap, mods, section_length))
+    } else {
+        None
+    };
+
+    let sections: Vec<Value> = strains
+        .strains
+        .iter()
+        .enumerate()
+        .map(|(i, strain)| match &osu {
+            Some(osu) => json!({
+                "time": time(i),
+                "strain": strain,
+                "aim": osu.aim.get(i).copied().unwrap_or(0.0),
+                "speed": osu.speed.get(i).copied().unwrap_or(0.0),
+            }),
+            None => json!({ "time": time(i), "strain": strain }),
+        })
+        .collect();
+    let mean = if sections.is_empty() {
+        0.0
+    } else {
+        strains.strains.iter().sum::<f32>() / sections.len() as f32
+    };
+
+    let (max_i, max) = peak(&strains.strains);
+    let mut value = json!({
+        "mode": beatmap.mode as u8,
+        "section_length": section_length,
+        "peak": { "time": time(max_i), "strain": max },
+        "mean": mean,
+        "sections": sections,
+    });
+    if let Some(osu) = &osu {
+        let sections = strains.strains.len();
+        let (aim_i, aim) = peak(&osu.aim[..osu.aim.len().min(sections)]);
+        let (speed_i, speed) = peak(&osu.speed[..osu.speed.len().min(sections)]);
+        value["aim_peak"] = json!({ "time": time(aim_i), "strain": aim });
+        value["speed_peak"] = json!({ "time": time(speed_i), "strain": speed });
+    };
+    value
+}
+
+/// Every point of a gradual calculation costs up to `objects`,
+/// so there are at most `max_work / objects` points (and at least one)
+#[inline(always)]
+pub fn gradual_max_points(objects: usize, max_points: usize, max_work: usize) -> usize {
+    max_points.min(max_work / objects.max(1)).max(1)
+}
+
+/// Passed object counts to calculate a gradual curve at,
+/// by object stride or by time step, the last object is always included.
+/// Without both, the stride spreads `max_points` points over the beatmap.
+pub fn gradual_points(
+    beatmap: &PPbeatmap,
+    stride: Option<usize>,
+    time_step: Option<f32>,
+    max_points: usize,
+) -> Vec<usize> {
+    let total = beatmap.hit_objects.len();
+    let max_points = max_points.max(1);
+    let mut points = Vec::new();
+    match (
+        time_step.filter(|t| *t > 0.0),
+        beatmap.hit_objects.first(),
+        beatmap.hit_objects.last(),
+    ) {
+        (Some(step), Some(first), Some(last)) => {
+            let first = first.start_time;
+            let duration = last.start_time - first;
+            let step = step
+                .max(MIN_GRADUAL_TIME_STEP)
+                .max(duration / max_points as f32);
+            // Boundaries are `first + k * step`, the next one is the first after this object
+            let mut next = first + step;
+            for (i, h) in beatmap.hit_objects.iter().enumerate() {
+                if h.start_time >= next {
+                    points.push(i + 1);
+                    let k = ((h.start_time - first) / step).floor() + 1.0;
+                    next = first + k * step;
+                }
+            }
+        }
+        _ => {
+            let stride = stride
+                .unwrap_or_else(|| (total + max_points - 1) / max_points)
+                .max(1);
+            points.extend((stride..=total).step_by(stride));
+        }
+    };
+    if points.last() != Some(&total) && total > 0 {
+        points.push(total);
+    };
+    // Too many points, keep every n-th of them
+    if points.len() > max_points && max_points > 0 {
+        let n = (points.len() + max_points - 1) / max_points;
+        let last = points[points.len() - 1];
+        points = points.into_iter().step_by(n).collect();
+        if points.last() != Some(&last) {
+            points.push(last);
+        };
+    };
+    points
+}
+
+/// Stars and pp after each of the passed object counts.
+/// peace-performance has no incremental calculation, so every point is a full calculation
+/// of the passed part: the cost is about `points * objects`, keep the points bounded.
+pub async fn calculate_gradual(beatmap: &PPbeatmap, data: &CalcData, points: &[usize]) -> Value {
+    let mut values = Vec::with_capacity(points.len());
+    for &passed in points {
+        // Hit counts of a whole play don't apply to a part of it
+        let point_data = CalcData {
+            passed_obj: Some(passed),
+            n300: None,
+            n100: None,
+            n50: None,
+            katu: None,
+            combo: None,
+            score: None,
+            miss: data.miss.map(|m| m.min(passed)),
+            ..data.clone()
+        };
+        let r = calculate_pp(beatmap, &point_data).await;
+        values.push(json!({
+            "passed_obj": passed,
+            "time": beatmap.hit_objects.get(passed - 1).map(|h| h.start_time),
+            "stars": r.attributes.stars(),
+            "pp": r.pp(),
+        }));
+    }
+    Value::Array(values)
+}
+
+/// Invalid `rate` requests
+#[inline(always)]
+pub fn validate_rate(data: &CalcData) -> Result<(), String> {
+    if let Some(rate) = data.rate {
+        if !(MIN_CLOCK_RATE..=MAX_CLOCK_RATE).contains(&rate) {
+            return Err(format!(
+                "invalid rate, must be in {} ~ {}",
+                MIN_CLOCK_RATE, MAX_CLOCK_RATE
+            ));
+        };
+        if data.mods.unwrap_or(0) & CLOCK_RATE_MODS > 0 {
+            return Err("rate replaces the DT / HT / NC mods, use one of them".to_string());
+        };
+    };
+    Ok(())
+}
+
+/// Judgements mapped for the calculators, and a copy of the map played at `rate` (if any).
+/// Every calculation of a request starts from these.
+pub fn prepare_beatmap(beatmap: Data<PPbeatmap>, data: CalcData) -> (CalcData, Data<PPbeatmap>) {
+    let mut data = normalize_judgements(&beatmap, data);
+    match data.rate {
+        Some(rate) => {
+            let mode = calc_mode(&beatmap, &data);
+            let (map, mods) = apply_clock_rate(&beatmap, mode, data.mods.unwrap_or(0), rate);
+            data.requested_mods = data.mods;
+            data.mods = Some(mods);
+            (data, Data::new(map))
+        }
+        None => (data, beatmap),
+    }
+}
+
+/// A score ready to be calculated
+pub struct PreparedScore {
+    /// The map at the requested rate
+    pub beatmap: Data<PPbeatmap>,
+    /// With simulated hit counts and lazer accuracy
+    pub data: CalcData,
+    /// The hit counts simulated from `acc`
+    pub simulated: Option<CalcData>,
+    pub scoring: ScoringModel,
+    pub algorithm: Arc<dyn PPAlgorithm>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrepareError {
+    Rate(String),
+    HitCounts(InvalidHitCounts),
+    UnknownAlgorithm(Option<String>),
+}
+
+impl PrepareError {
+    pub fn error_message(&self) -> String {
+        match self {
+            Self::Rate(message) => message.clone(),
+            Self::HitCounts(err) => err.error_message(),
+            Self::UnknownAlgorithm(name) => format!(
+                "unknown algorithm {}, see /api/algorithms",
+                name.as_deref().unwrap_or("default")
+            ),
+        }
+    }
+
+    #[inline(always)]
+    pub fn json(&self) -> Value {
+        match self {
+            Self::HitCounts(err) => err.json(),
+            _ => json!({ "status": 0, "message": self.error_message(), "pp": null }),
+        }
+    }
+}
+
+/// Everything before a score is calculated, the same for `/api/calc` and the auto
+/// recalculation: rate, judgements and hit counts checked, hit counts simulated from `acc`,
+/// lazer accuracy, and the algorithm it is calculated with.
+pub async fn prepare_score(
+    beatmap: Data<PPbeatmap>,
+    data: CalcData,
+    algorithms: &AlgorithmRegistry,
+) -> Result<PreparedScore, PrepareError> {
+    validate_rate(&data).map_err(PrepareError::Rate)?;
+    let (data, beatmap) = prepare_beatmap(beatmap, data);
+    // Impossible hit counts, don't give them pp
+    validate_hit_counts(&beatmap, &data).map_err(PrepareError::HitCounts)?;
+
+    // Only acc, calculate with the hit counts it simulates
+    let simulated = simulate_hit_counts(&beatmap, &data);
+    let mut data = simulated.clone().unwrap_or(data);
+
+    // lazer results are calculated with their accuracy, slider tails and ticks included
+    let scoring = ScoringModel::of(&data);
+    if scoring == ScoringModel::Lazer {
+        if let Some(acc) = lazer_accuracy(&beatmap, &data) {
+            data.acc = Some(acc);
+        };
+    };
+
+    let algorithm = algorithms
+        .for_request(&data)
+        .await
+        .ok_or_else(|| PrepareError::UnknownAlgorithm(data.algo.clone()))?;
+    Ok(PreparedScore {
+        beatmap,
+        data,
+        simulated,
+        scoring,
+        algorithm,
+    })
+}
+
 #[inline(always)]
 pub fn mode_calculator(mode: u8, beatmap: &PPbeatmap) -> AnyPP {
     match mode {
@@ -154,7 +1274,7 @@ pub async fn get_beatmap(
     sid: Option<i32>,
     file_name: Option<String>,
     glob: &Glob,
-) -> Option<Data<PPbeatmap>> {
+) -> Result<Data<PPbeatmap>, GetBeatmapFailed> {
     let b = glob
         .caches
         .beatmap_cache
@@ -174,6 +1294,30 @@ pub async fn get_beatmap(
         }
     };
 
+    // Resolve md5 with local index, then we can use the md5 caches.
+    // A stale mapping is re-checked upstream first, and only used if that fails
+    let mut stale_md5 = None;
+    if md5.is_none() {
+        let index = &glob.beatmap_index;
+        for &allow_stale in [false, true].iter() {
+            let indexed = match (bid, sid, file_name.as_ref()) {
+                (Some(bid), _, _) => index.get_by_bid(bid, allow_stale).await,
+                (None, Some(sid), Some(file_name)) => {
+                    index.get_by_sid_file(sid, file_name, allow_stale).await
+                }
+                _ => None,
+            };
+            if indexed.is_some() {
+                if allow_stale {
+                    stale_md5 = indexed;
+                } else {
+                    md5 = indexed;
+                };
+                break;
+            };
+        }
+    };
+
     if let Ok(b) = get_beatmap_from_local(
         md5.as_ref(),
         bid,
@@ -182,9 +1326,102 @@ pub async fn get_beatmap(
     )
     .await
     {
-        return Some(b);
+        return Ok(b);
+    };
+
+    // Known missing or broken beatmap, don't ask upstream again until it expires
+    let negative_key = negative_cache_key(md5.as_ref(), bid, sid, file_name.as_ref());
+    if let Some((error, ttl)) = glob.caches.get_negative(&negative_key).await {
+        if let Some(b) = get_stale_beatmap(stale_md5.as_ref(), glob).await {
+            return Ok(b);
+        };
+        debug!(
+            "[calculate_pp] Beatmap {} is in negative cache ({}), ttl: {}s",
+            negative_key,
+            error.kind(),
+            ttl
+        );
+        return Err(GetBeatmapFailed {
+            error,
+            negative_cache_ttl: Some(ttl),
+        });
+    };
+
+    match get_beatmap_from_api(md5.as_ref(), bid, sid, file_name.as_ref(), glob).await {
+        Ok(b) => Ok(b),
+        Err(error) => {
+            glob.caches.cache_negative(negative_key, error).await;
+            if let Some(b) = get_stale_beatmap(stale_md5.as_ref(), glob).await {
+                return Ok(b);
+            };
+            Err(GetBeatmapFailed::new(error))
+        }
+    }
+}
+
+/// Could not re-check a stale index mapping upstream, its file is better than nothing
+#[inline(always)]
+async fn get_stale_beatmap(stale_md5: Option<&String>, glob: &Glob) -> Option<Data<PPbeatmap>> {
+    let md5 = stale_md5?;
+    debug!("[calculate_pp] Use stale indexed beatmap {}", md5);
+    get_beatmap_from_local(
+        Some(md5),
+        None,
+        &glob.local_config.data.osu_files_dir,
+        &glob.caches,
+    )
+    .await
+    .ok()
+}
+
+/// Resolve the stored .osu file (md5) of a beatmap, with the same path as `get_beatmap`:
+/// local index and files first, then fetch it (if not offline).
+pub async fn resolve_osu_file(
+    md5: Option<String>,
+    bid: Option<i32>,
+    glob: &Glob,
+) -> Result<String, GetBeatmapFailed> {
+    let dir = &glob.local_config.data.osu_files_dir;
+    let exists = |md5: &String| std::path::Path::new(&format!("{}/{}.osu", dir, md5)).exists();
+
+    let indexed = match (&md5, bid) {
+        (Some(md5), _) => Some(md5.clone()),
+        (None, Some(bid)) => glob.beatmap_index.get_by_bid(bid, false).await,
+        _ => None,
     };
-    get_beatmap_from_api(md5.as_ref(), bid, sid, file_name.as_ref(), glob).await
+    if let Some(md5) = indexed.filter(|m| exists(m)) {
+        return Ok(md5);
+    };
+
+    // Not stored yet, get it (downloads, saves and indexes it)
+    get_beatmap(md5.clone(), bid, None, None, glob).await?;
+    let md5 = match (md5, bid) {
+        (Some(md5), _) => Some(md5),
+        (None, Some(bid)) => glob.beatmap_index.get_by_bid(bid, true).await,
+        _ => None,
+    };
+    md5.filter(|m| exists(m))
+        .ok_or(GetBeatmapFailed::new(GetBeatmapError::FileNotFound))
+}
+
+#[inline(always)]
+pub fn negative_cache_key(
+    md5: Option<&String>,
+    bid: Option<i32>,
+    sid: Option<i32>,
+    file_name: Option<&String>,
+) -> String {
+    match (md5, bid) {
+        (Some(md5), _) => md5.clone(),
+        (None, Some(bid)) => format!("bid_{}", bid),
+        (None, None) => format!(
+            "sid_{}/{}",
+            sid.unwrap_or_default(),
+            file_name
+                .map(|f| osu_files::normalize_file_name(f))
+                .unwrap_or_default()
+        ),
+    }
 }
 
 #[inline(always)]
@@ -245,6 +1482,34 @@ pub async fn get_beatmap_from_local(
     Err(GetBeatmapError::FileNotFound)
 }
 
+/// Save a downloaded .osu file locally, and index it
+pub async fn store_osu_file(
+    bytes: &Bytes,
+    md5: &String,
+    bid: i32,
+    sid: Option<i32>,
+    file_name: Option<&String>,
+    glob: &Glob,
+) -> bool {
+    if !osu_files::write_osu_file_atomic(bytes, &glob.local_config.data.osu_files_dir, md5).await {
+        return false;
+    };
+    let metadata = osu_files::OsuFileMetadata::from_bytes(bytes);
+    // The file name only belongs to the sid we asked for if it matches the metadata
+    let sid = sid.filter(|_| {
+        file_name.map_or(false, |f| {
+            osu_files::normalize_file_name(f)
+                == osu_files::normalize_file_name(&metadata.file_name())
+        })
+    });
+    // Saved by the index writer
+    glob.beatmap_index
+        .insert_metadata(md5, &metadata, Some(bid), sid)
+        .await;
+    glob.beatmap_index.mark_checked(md5).await;
+    true
+}
+
 #[inline(always)]
 pub async fn get_beatmap_from_api(
     request_md5: Option<&String>,
@@ -252,58 +1517,90 @@ pub async fn get_beatmap_from_api(
     sid: Option<i32>,
     file_name: Option<&String>,
     glob: &Glob,
-) -> Option<Data<PPbeatmap>> {
+) -> Result<Data<PPbeatmap>, GetBeatmapError> {
+    // All outbound fetching goes through here
+    if glob.is_offline() {
+        debug!(
+            "[calculate_pp] Offline mode, skip fetching beatmap {:?}({:?})",
+            request_md5, bid
+        );
+        return Err(GetBeatmapError::Offline);
+    };
     let start = Instant::now();
-    let bid = if bid.is_none() {
+    // With osu!api v2, also look up known bids, so unknown ones never reach the download sources
+    let bid = if glob.local_config.data.osu_api.version == 2 {
+        if glob.osu_api_v2_limiter.acquire().await.is_err() {
+            return Err(GetBeatmapError::RateLimited);
+        };
+        match glob
+            .osu_api_v2
+            .resolve_bid(request_md5, bid, sid, file_name)
+            .await
+        {
+            Ok(bid) => bid,
+            Err(err) => {
+                warn!(
+                    "[calculate_pp] Cannot get beatmap from osu!api v2, md5: {:?}, bid: {:?}, sid: {:?}, file_name: {:?}, err: {:?}",
+                    request_md5, bid, sid, file_name, err
+                );
+                return Err(match err {
+                    OsuApiV2Error::NotFound => GetBeatmapError::NotFound,
+                    _ => GetBeatmapError::Unavailable,
+                });
+            }
+        }
+    } else if bid.is_none() {
         #[cfg(feature = "with_peace")]
         let expires = glob.config.read().await.data.beatmaps.cache_expires;
-        #[cfg(feature = "with_peace")]
-        let osu_api = glob.osu_api.read().await;
-
         #[cfg(not(feature = "with_peace"))]
         let expires = glob.local_config.data.beatmap_cache_timeout as i64;
-        #[cfg(not(feature = "with_peace"))]
-        let osu_api = &glob.osu_api;
+
+        // Rotate keys, each of them has its own budget
+        let (_, osu_api) = glob
+            .osu_api_limiter
+            .acquire()
+            .await
+            .map_err(|_| GetBeatmapError::RateLimited)?;
         peace_objects::beatmaps::Beatmap::get(
             request_md5,
             None,
             sid,
             file_name,
-            &osu_api,
+            osu_api,
             #[cfg(feature = "with_peace")]
             glob.database.get_ref(),
             true,
             &glob.caches.beatmap_cache,
             expires,
         )
-        .await?
+        .await
+        .ok_or(GetBeatmapError::NotFound)?
         .id
     } else {
         bid.unwrap()
     };
-    // Download beatmap, and try parse it
-    #[cfg(feature = "with_peace")]
-    let osu_api = glob.osu_api.read().await;
-    #[cfg(not(feature = "with_peace"))]
-    let osu_api = &glob.osu_api;
-
-    let (b, new_md5, bytes) = match osu_api.get_pp_beatmap(bid).await {
-        Ok((b, new_md5, bytes)) => (b, new_md5, bytes),
-        Err(err) => {
+    // Download beatmap from sources, and verify it
+    let dir = &glob.local_config.data.osu_files_dir;
+    let (b, new_md5, bytes) = match glob.downloader.download(bid, request_md5, dir).await {
+        Ok(r) => r,
+        Err(failed) => {
             warn!(
-                "[calculate_pp] Cannot get .osu file from osu!api, err: {:?}",
-                err
+                "[calculate_pp] Cannot get .osu file from any source, bid: {}, err: {:?}",
+                bid, failed.error
             );
-            return None;
+            // The requested md5 is outdated, keep the current version of the map
+            if let Some(other) = failed.other_version {
+                if store_osu_file(&other.bytes, &other.md5, bid, None, None, glob).await {
+                    glob.caches
+                        .invalidate_beatmap(&other.md5, Some(bid), None)
+                        .await;
+                };
+            };
+            return Err(failed.error);
         }
     };
 
-    // Save .osu file locally
-    write_osu_file(
-        bytes,
-        format!("{}/{}.osu", glob.local_config.data.osu_files_dir, new_md5),
-    )
-    .await;
+    store_osu_file(&bytes, &new_md5, bid, sid, file_name, glob).await;
 
     // Cache it
     let c = PPbeatmapCache::new(b);
@@ -315,32 +1612,328 @@ pub async fn get_beatmap_from_api(
         .cache_pp_beatmap(format!("bid_{}", bid), c)
         .await;
 
-    // Check .osu file is same md5
-    if request_md5.is_some() && request_md5.unwrap() != &new_md5 {
-        warn!("[calculate_pp] Success get .osu file from api, but md5 not eq.");
-        return None;
-    }
-
     info!(
         "[calculate_pp] Success get .osu file from api, bid: {:?}, md5: {:?}; time spent: {:?}",
         bid,
-        request_md5,
+        new_md5,
         start.elapsed()
     );
 
-    Some(b)
+    Ok(b)
 }
 
-#[inline(always)]
-pub async fn write_osu_file(bytes: Bytes, path: String) -> bool {
-    match tokio::fs::write(path, bytes).await {
-        Ok(_) => true,
-        Err(err) => {
-            warn!(
-                "[calculate_pp] Failed to write into .osu file locally, err: {:?}",
-                err
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::test_utils::osu_file;
+
+    // 3 objects at 1000, 1500 and 2000ms
+    async fn beatmap() -> PPbeatmap {
+        PPbeatmap::parse(osu_file(1, "Normal").as_bytes())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn gradual_default_stride_follows_max_points() {
+        let b = beatmap().await;
+        assert_eq!(gradual_points(&b, None, None, 1000), vec![1, 2, 3]);
+        assert_eq!(gradual_points(&b, None, None, 2), vec![2, 3]);
+        assert_eq!(gradual_points(&b, Some(2), None, 1000), vec![2, 3]);
+    }
+
+    #[test]
+    fn gradual_work_is_bounded() {
+        assert_eq!(gradual_max_points(1000, 100, 1_000_000), 100);
+        assert_eq!(gradual_max_points(20_000, 100, 1_000_000), 50);
+        assert_eq!(gradual_max_points(0, 100, 1_000_000), 100);
+        assert_eq!(gradual_max_points(2_000_000, 100, 1_000_000), 1);
+    }
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!(
+            (value - expected).abs() < 1e-3,
+            "{} is not {}",
+            value,
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn clock_rate_scales_ar_od_and_times() {
+        // AR 9, OD 8
+        let b = beatmap().await;
+        let (map, mods) = apply_clock_rate(&b, 0, 0, 1.5);
+        // Approach time 600ms -> 400ms
+        assert_close(map.ar, 10.0 + 1.0 / 3.0);
+        // 300 hit window 32ms -> 21.33ms
+        assert_close(map.od, 9.0 + 7.0 / 9.0);
+        assert_close(map.hit_objects[0].start_time, 1000.0 / 1.5);
+        assert_eq!(mods, 0);
+
+        let (map, _) = apply_clock_rate(&b, 0, 0, 0.75);
+        // Approach time 600ms -> 800ms
+        assert_close(map.ar, 7.0 + 2.0 / 3.0);
+        assert_close(map.hit_objects[2].start_time, 2000.0 / 0.75);
+    }
+
+    #[tokio::test]
+    async fn clock_rate_applies_hr_first() {
+        let b = beatmap().await;
+        // HR: AR 10, OD 10, then approach time 450ms -> 300ms, hit window 20ms -> 13.33ms
+        let (map, mods) = apply_clock_rate(&b, 0, 16 | 8, 1.5);
+        assert_close(map.ar, 11.0);
+        assert_close(map.od, 11.0 + 1.0 / 9.0);
+        assert_eq!(mods, 8);
+    }
+
+    #[tokio::test]
+    async fn clock_rate_mania_hit_window() {
+        let b = beatmap().await;
+        // Perfect hit window 40ms -> 26.67ms, HR is left to the calculator
+        let (map, mods) = apply_clock_rate(&b, 3, 16, 1.5);
+        assert_close(map.od, 12.0 + 4.0 / 9.0);
+        assert_eq!(mods, 16);
+        // osu!taiko great hit window 26ms -> 17.33ms
+        let (map, _) = apply_clock_rate(&b, 1, 0, 1.5);
+        assert_close(map.od, 10.0 + 8.0 / 9.0);
+    }
+
+    #[tokio::test]
+    async fn strain_timeline_follows_mode() {
+        let b = beatmap().await;
+        let std = strain_timeline(&b, 0, 0);
+        assert_eq!(std["mode"], 0);
+        let sections = std["sections"].as_array().unwrap();
+        assert!(!sections.is_empty());
+        assert!(sections
+            .iter()
+            .all(|s| s["aim"].is_number() && s["speed"].is_number()));
+        assert!(std["aim_peak"]["strain"].as_f64().unwrap() > 0.0);
+        assert!(std["speed_peak"]["strain"].as_f64().unwrap() > 0.0);
+
+        let taiko = strain_timeline(&b, 1, 0);
+        assert_eq!(taiko["mode"], 1);
+        assert!(taiko.get("aim_peak").is_none());
+        assert!(taiko["sections"][0].get("aim").is_none());
+    }
+
+    #[tokio::test]
+    async fn gradual_time_step() {
+        let b = beatmap().await;
+        assert_eq!(gradual_points(&b, None, Some(500.0), 1000), vec![2, 3]);
+        assert_eq!(gradual_points(&b, None, Some(800.0), 1000), vec![3]);
+        // Tiny steps are raised to the minimum instead of looping forever
+        assert_eq!(
+            gradual_points(&b, None, Some(f32::MIN_POSITIVE), 1000),
+            vec![2, 3]
+        );
+        assert_eq!(
+            gradual_points(&b, None, Some(f32::MIN_POSITIVE), 1),
+            vec![3]
+        );
+    }
+
+    #[tokio::test]
+    async fn if_fc_ctb_removes_misses_only() {
+        let b = beatmap().await;
+        // Fruits, droplets, tiny droplets, missed tiny droplets, missed fruits and droplets
+        let data: CalcData = serde_json::from_value(
+            json!({"mode": 2, "n300": 10, "n100": 5, "n50": 80, "katu": 5, "miss": 2}),
+        )
+        .unwrap();
+        let fc = if_fc_data(&b, &data);
+        assert_eq!(fc.miss, Some(0));
+        assert!((fc.acc.unwrap() - 95.0).abs() < 0.001);
+        assert_eq!((fc.n300, fc.katu), (None, None));
+    }
+
+    #[tokio::test]
+    async fn if_fc_mania_projects_score() {
+        let b = beatmap().await;
+        let data: CalcData = serde_json::from_value(json!({
+            "mode": 3, "geki": 50, "n300": 30, "katu": 10, "n100": 5, "miss": 5, "score": 700000
+        }))
+        .unwrap();
+        let fc = if_fc_data(&b, &data);
+        assert_eq!(fc.miss, Some(0));
+        assert_eq!(fc.score, Some(904605));
+        assert!((fc.acc.unwrap() - 26500.0 / 28500.0 * 100.0).abs() < 0.001);
+
+        // No judgements to project, keep the score instead of an SS
+        let data: CalcData = serde_json::from_value(json!({"mode": 3, "score": 700000})).unwrap();
+        assert_eq!(if_fc_data(&b, &data).score, Some(700000));
+    }
+
+    fn calc_data(value: Value) -> CalcData {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn hit_counts_are_validated_per_mode() {
+        let b = beatmap().await;
+        let validate = |value: Value| validate_hit_counts(&b, &calc_data(value));
+
+        // osu!std
+        assert_eq!(validate(json!({"n300": 2, "n100": 1})), Ok(()));
+        assert_eq!(
+            validate(json!({"n300": 3, "miss": 1})),
+            Err(InvalidHitCounts::TooManyHits { hits: 4, max: 3 })
+        );
+        assert_eq!(
+            validate(json!({"n300": 3, "passed_obj": 2})),
+            Err(InvalidHitCounts::TooManyHits { hits: 3, max: 2 })
+        );
+        assert_eq!(
+            validate(json!({"passed_obj": 4})),
+            Err(InvalidHitCounts::PassedObjects { passed: 4, max: 3 })
+        );
+        assert_eq!(
+            validate(json!({"acc": 100.5})),
+            Err(InvalidHitCounts::Accuracy(100.5))
+        );
+        assert_eq!(
+            validate(json!({"n300": 3, "slider_tail_hit": 1})),
+            Err(InvalidHitCounts::TooManyHits { hits: 1, max: 0 })
+        );
+
+        // osu!taiko
+        assert_eq!(validate(json!({"mode": 1, "n300": 2, "miss": 1})), Ok(()));
+        assert_eq!(
+            validate(json!({"mode": 1, "n300": 2, "n50": 1})),
+            Err(InvalidHitCounts::UnexpectedHits {
+                field: "n50",
+                count: 1
+            })
+        );
+        assert_eq!(
+            validate(json!({"mode": 1, "n300": 4})),
+            Err(InvalidHitCounts::TooManyHits { hits: 4, max: 3 })
+        );
+        assert_eq!(
+            validate(json!({"mode": 1, "n300": 3, "slider_tail_hit": 0})),
+            Err(InvalidHitCounts::UnexpectedHits {
+                field: "slider_tail_hit",
+                count: 0
+            })
+        );
+
+        // osu!ctb judgements depend on the droplets, only the combo is checked
+        assert_eq!(
+            validate(json!({"mode": 2, "n300": 100, "n50": 300})),
+            Ok(())
+        );
+
+        // osu!mania, katu is n200
+        assert_eq!(
+            validate(json!({"mode": 3, "geki": 1, "n300": 1, "katu": 1})),
+            Ok(())
+        );
+        assert_eq!(
+            validate(json!({"mode": 3, "geki": 2, "n300": 1, "katu": 1})),
+            Err(InvalidHitCounts::TooManyHits { hits: 4, max: 3 })
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_hit_counts_follow_strategy() {
+        let b = beatmap().await;
+        let simulate = |value: Value| {
+            simulate_hit_counts(&b, &calc_data(value)).map(|d| {
+                (
+                    d.n300.unwrap(),
+                    d.n100.unwrap(),
+                    d.n50.unwrap(),
+                    d.miss.unwrap(),
+                )
+            })
+        };
+
+        // osu!std, SS with any strategy
+        for strategy in &["prefer_100", "prefer_50", "even"] {
+            assert_eq!(
+                simulate(json!({"acc": 100.0, "strategy": strategy})),
+                Some((3, 0, 0, 0))
             );
-            return false;
         }
+        // Lowest accuracy without misses
+        assert_eq!(
+            simulate(json!({"acc": 0.0, "strategy": "prefer_100"})),
+            Some((0, 0, 3, 0))
+        );
+        assert_eq!(
+            simulate(json!({"acc": 0.0, "strategy": "prefer_50"})),
+            Some((0, 0, 3, 0))
+        );
+        // Odd object left over as a 300
+        assert_eq!(
+            simulate(json!({"acc": 0.0, "strategy": "even"})),
+            Some((1, 1, 1, 0))
+        );
+        assert_eq!(
+            simulate(json!({"acc": 50.0, "miss": 1})),
+            Some((1, 1, 0, 1))
+        );
+
+        // Counts over the object count are capped
+        assert_eq!(
+            simulate(json!({"acc": 100.0, "passed_obj": 10})),
+            Some((3, 0, 0, 0))
+        );
+        assert_eq!(
+            simulate(json!({"acc": 100.0, "miss": 5})),
+            Some((0, 0, 0, 3))
+        );
+        assert_eq!(
+            simulate(json!({"acc": 100.0, "passed_obj": 2})),
+            Some((2, 0, 0, 0))
+        );
+
+        // osu!taiko only has 100s
+        for strategy in &["prefer_100", "prefer_50", "even"] {
+            assert_eq!(
+                simulate(json!({"mode": 1, "acc": 100.0, "strategy": strategy})),
+                Some((3, 0, 0, 0))
+            );
+            assert_eq!(
+                simulate(json!({"mode": 1, "acc": 0.0, "strategy": strategy})),
+                Some((0, 3, 0, 0))
+            );
+        }
+
+        // Not simulated: given hit counts, no acc, osu!ctb and osu!mania
+        assert_eq!(simulate(json!({"acc": 90.0, "n300": 2})), None);
+        assert_eq!(simulate(json!({"miss": 1})), None);
+        assert_eq!(simulate(json!({"mode": 2, "acc": 90.0})), None);
+        assert_eq!(simulate(json!({"mode": 3, "acc": 90.0})), None);
+    }
+
+    #[tokio::test]
+    async fn lazer_accuracy_counts_tails_and_ticks() {
+        // The object at 1500ms is a slider
+        let file = osu_file(1, "Normal")
+            .replace("128,96,1500,1,0,0:0:0:0:", "128,96,1500,2,0,L|200:96,1,70");
+        let b = PPbeatmap::parse(file.as_bytes()).await.unwrap();
+        let acc = |value: Value| lazer_accuracy(&b, &calc_data(value));
+
+        assert_eq!(acc(json!({"n300": 3})), Some(100.0));
+        assert_eq!(acc(json!({"n300": 3, "slider_tail_hit": 1})), Some(100.0));
+        assert_eq!(acc(json!({"miss": 3, "slider_tail_hit": 0})), Some(0.0));
+        assert_close(
+            acc(json!({"n300": 3, "slider_tail_hit": 0})).unwrap(),
+            900.0 / 1050.0 * 100.0,
+        );
+        assert_close(
+            acc(json!({"n300": 2, "n100": 1, "large_tick_hit": 2, "large_tick_miss": 2})).unwrap(),
+            760.0 / 1020.0 * 100.0,
+        );
+        // The slider is not passed yet
+        assert_eq!(
+            acc(json!({"n300": 1, "slider_tail_hit": 0, "passed_obj": 1})),
+            Some(100.0)
+        );
+        assert_eq!(acc(json!({})), None);
+        // Other modes are as in v1
+        assert_eq!(acc(json!({"mode": 1, "n300": 2, "n100": 2})), Some(75.0));
     }
 }
@@ -0,0 +1,481 @@
+use {
+    bytes::Bytes,
+    chrono::Local,
+    peace_performance::Beatmap as PPbeatmap,
+    serde_json::{json, Value},
+    std::{
+        sync::atomic::{AtomicI64, AtomicU32, AtomicU64, Ordering},
+        time::Duration,
+    },
+};
+
+use crate::objects::{calculator::GetBeatmapError, osu_files};
+use crate::settings::model::{BeatmapDownload, BeatmapSource};
+
+#[derive(Default)]
+pub struct SourceHealth {
+    pub consecutive_failures: AtomicU32,
+    pub unhealthy_until: AtomicI64,
+    pub success: AtomicU64,
+    pub failed: AtomicU64,
+}
+
+/// A valid .osu file of the beatmap, but not the requested md5 (the map was updated)
+pub struct OtherVersion {
+    pub md5: String,
+    pub bytes: Bytes,
+}
+
+pub struct DownloadFailed {
+    pub error: GetBeatmapError,
+    /// Store it under its own md5, it is still the current version of the beatmap
+    pub other_version: Option<OtherVersion>,
+}
+
+pub enum DownloadError {
+    /// Source answered, but does not have this beatmap
+    NotFound,
+    /// Source is unreachable or answered with an error
+    Unavailable(String),
+}
+
+/// Longest wait between two attempts of a source (ms)
+pub const MAX_RETRY_BACKOFF: u64 = 60_000;
+
+/// `backoff * 2^(attempt - 1)`, at most `MAX_RETRY_BACKOFF`
+#[inline(always)]
+pub fn retry_backoff(backoff: u64, attempt: u32) -> u64 {
+    2u64.checked_pow(attempt.saturating_sub(1))
+        .map_or(u64::MAX, |m| backoff.saturating_mul(m))
+        .min(MAX_RETRY_BACKOFF)
+}
+
+/// Download .osu files from an ordered list of sources (official endpoint, mirrors...).
+/// Each source gets its own timeout and retries; a source that keeps failing
+/// is skipped for a while, so an upstream outage falls through to the next one.
+pub struct BeatmapDownloader {
+    pub client: reqwest::Client,
+    pub sources: Vec<(BeatmapSource, SourceHealth)>,
+    pub max_failures: u32,
+    pub cooldown: i64,
+}
+
+impl BeatmapDownloader {
+    pub fn new(config: &BeatmapDownload) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            sources: config
+                .sources
+                .iter()
+                .map(|s| (s.clone(), SourceHealth::default()))
+                .collect(),
+            max_failures: config.max_failures,
+            cooldown: config.cooldown as i64,
+        }
+    }
+
+    #[inline(always)]
+    fn is_healthy(health: &SourceHealth) -> bool {
+        health.unhealthy_until.load(Ordering::SeqCst) <= Local::now().timestamp()
+    }
+
+    #[inline(always)]
+    fn mark_success(health: &SourceHealth) {
+        health.success.fetch_add(1, Ordering::SeqCst);
+        health.consecutive_failures.store(0, Ordering::SeqCst);
+        health.unhealthy_until.store(0, Ordering::SeqCst);
+    }
+
+    #[inline(always)]
+    fn mark_failed(&self, source: &BeatmapSource, health: &SourceHealth) {
+        health.failed.fetch_add(1, Ordering::SeqCst);
+        let failures = health.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= self.max_failures {
+            warn!(
+                "[beatmap_downloader] Source '{}' failed {} times in a row, skip it for {}s",
+                source.name, failures, self.cooldown
+            );
+            health
+                .unhealthy_until
+                .store(Local::now().timestamp() + self.cooldown, Ordering::SeqCst);
+        }
+    }
+
+    /// Request one source, with retry and exponential backoff
+    pub async fn download_from(
+        &self,
+        source: &BeatmapSource,
+        bid: i32,
+    ) -> Result<Bytes, DownloadError> {
+        let url = source.url.replace("{bid}", &bid.to_string());
+        let mut last_err = String::new();
+        for attempt in 0..=source.retry {
+            if attempt > 0 {
+                tokio::time::sleep(Duration::from_millis(retry_backoff(
+                    source.retry_backoff,
+                    attempt,
+                )))
+                .await;
+            };
+            let resp = self
+                .client
+                .get(&url)
+                .timeout(Duration::from_secs(source.timeout))
+                .send()
+                .await;
+            match resp {
+                Ok(resp) if resp.status().as_u16() == 404 => return Err(DownloadError::NotFound),
+                Ok(resp) if resp.status().is_success() => match resp.bytes().await {
+                    // osu! answers 200 with an empty body for unknown beatmaps
+                    Ok(bytes) if bytes.is_empty() => return Err(DownloadError::NotFound),
+                    Ok(bytes) => return Ok(bytes),
+                    Err(err) => last_err = format!("{:?}", err),
+                },
+                Ok(resp) => last_err = format!("status {}", resp.status()),
+                Err(err) => last_err = format!("{:?}", err),
+            };
+            debug!(
+                "[beatmap_downloader] Source '{}' attempt {} failed, bid: {}, err: {}",
+                source.name,
+                attempt + 1,
+                bid,
+                last_err
+            );
+        }
+        Err(DownloadError::Unavailable(last_err))
+    }
+
+    /// Try all sources in order until one of them gives a valid .osu file of the requested md5.
+    /// Unparsable files are quarantined and the next source is tried;
+    /// a valid file of another md5 is returned with the error, so it can be stored.
+    pub async fn download(
+        &self,
+        bid: i32,
+        request_md5: Option<&String>,
+        osu_files_dir: &String,
+    ) -> Result<(PPbeatmap, String, Bytes), DownloadFailed> {
+        let mut error = GetBeatmapError::NotFound;
+        let mut other_version = None;
+        // If every source is cooling down, try them all anyway
+        let all_unhealthy = self.sources.iter().all(|(_, h)| !Self::is_healthy(h));
+        for (source, health) in self.sources.iter() {
+            if !all_unhealthy && !Self::is_healthy(health) {
+                debug!(
+                    "[beatmap_downloader] Source '{}' is unhealthy, skip it.",
+                    source.name
+                );
+                continue;
+            };
+            let bytes = match self.download_from(source, bid).await {
+                Ok(bytes) => bytes,
+                Err(DownloadError::NotFound) => {
+                    Self::mark_success(health);
+                    debug!(
+                        "[beatmap_downloader] Source '{}' does not have beatmap {}",
+                        source.name, bid
+                    );
+                    continue;
+                }
+                Err(DownloadError::Unavailable(err)) => {
+                    warn!(
+                        "[beatmap_downloader] Source '{}' is unavailable, bid: {}, err: {}",
+                        source.name, bid, err
+                    );
+                    self.mark_failed(source, health);
+                    if error == GetBeatmapError::NotFound {
+                        error = GetBeatmapError::Unavailable;
+                    };
+                    continue;
+                }
+            };
+            match osu_files::verify_osu_file(&bytes, None).await {
+                // A md5 mismatch means the map was updated (or the mirror is outdated),
+                // that is "not found" for the requested md5, not a broken source
+                Ok((_, md5)) if request_md5.map_or(false, |m| m != &md5) => {
+                    Self::mark_success(health);
+                    debug!(
+                        "[beatmap_downloader] Source '{}' gave another version of beatmap {}, md5: {}, requested: {:?}",
+                        source.name, bid, md5, request_md5
+                    );
+                    if other_version.is_none() {
+                        other_version = Some(OtherVersion { md5, bytes });
+                    };
+                }
+                Ok((b, md5)) => {
+                    Self::mark_success(health);
+                    return Ok((b, md5, bytes));
+                }
+                Err(err) => {
+                    warn!(
+                        "[beatmap_downloader] Source '{}' gave an invalid .osu file, bid: {}, reason: {}",
+                        source.name,
+                        bid,
+                        err.reason()
+                    );
+                    osu_files::quarantine_osu_file(&bytes, osu_files_dir, bid, request_md5, &err)
+                        .await;
+                    self.mark_failed(source, health);
+                    error = GetBeatmapError::ParseError;
+                }
+            };
+        }
+        Err(DownloadFailed {
+            error,
+            other_version,
+        })
+    }
+
+    pub fn health(&self) -> Value {
+        let now = Local::now().timestamp();
+        Value::Array(
+            self.sources
+                .iter()
+                .map(|(source, health)| {
+                    let unhealthy_until = health.unhealthy_until.load(Ordering::SeqCst);
+                    json!({
+                        "name": source.name,
+                        "url": source.url,
+                        "healthy": unhealthy_until <= now,
+                        "unhealthy_until": unhealthy_until,
+                        "consecutive_failures": health.consecutive_failures.load(Ordering::SeqCst),
+                        "success": health.success.load(Ordering::SeqCst),
+                        "failed": health.failed.load(Ordering::SeqCst),
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::test_utils::{osu_file, temp_dir, StandIn};
+
+    fn source(name: &str, stand_in: &StandIn, retry: u32, retry_backoff: u64) -> BeatmapSource {
+        BeatmapSource {
+            name: name.to_string(),
+            url: format!("{}/osu/{{bid}}", stand_in.url),
+            timeout: 5,
+            retry,
+            retry_backoff,
+        }
+    }
+
+    fn downloader(sources: Vec<BeatmapSource>, max_failures: u32) -> BeatmapDownloader {
+        BeatmapDownloader::new(&BeatmapDownload {
+            max_failures,
+            cooldown: 60,
+            sources,
+        })
+    }
+
+    fn health_of(d: &BeatmapDownloader, i: usize) -> (u64, u64, u32) {
+        let h = &d.sources[i].1;
+        (
+            h.success.load(Ordering::SeqCst),
+            h.failed.load(Ordering::SeqCst),
+            h.consecutive_failures.load(Ordering::SeqCst),
+        )
+    }
+
+    #[tokio::test]
+    async fn fails_over_in_order() {
+        let broken = StandIn::start(|_, _| (500, String::new())).await;
+        let missing = StandIn::start(|_, _| (404, String::new())).await;
+        let mirror = StandIn::start(|_, _| (200, osu_file(42, "Normal"))).await;
+        let d = downloader(
+            vec![
+                source("broken", &broken, 0, 0),
+                source("missing", &missing, 0, 0),
+                source("mirror", &mirror, 0, 0),
+            ],
+            3,
+        );
+
+        let (_, md5, bytes) = d
+            .download(42, None, &temp_dir("failover"))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(md5, osu_files::bytes_md5(&bytes));
+        assert_eq!(mirror.requests()[0].path, "/osu/42");
+
+        let (b, m, r) = (
+            &broken.requests()[0],
+            &missing.requests()[0],
+            &mirror.requests()[0],
+        );
+        assert!(b.time <= m.time && m.time <= r.time);
+        assert_eq!(health_of(&d, 0), (0, 1, 1));
+        assert_eq!(health_of(&d, 1), (1, 0, 0));
+        assert_eq!(health_of(&d, 2), (1, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn retries_with_backoff() {
+        let flaky = StandIn::start(|_, i| match i {
+            0 | 1 => (502, String::new()),
+            _ => (200, osu_file(1, "Normal")),
+        })
+        .await;
+        let d = downloader(vec![source("flaky", &flaky, 2, 50)], 3);
+
+        assert!(d.download(1, None, &temp_dir("retry")).await.is_ok());
+        let requests = flaky.requests();
+        assert_eq!(requests.len(), 3);
+        // 50ms before the first retry, then 100ms
+        assert!(requests[1].time - requests[0].time >= Duration::from_millis(50));
+        assert!(requests[2].time - requests[1].time >= Duration::from_millis(100));
+        assert_eq!(health_of(&d, 0), (1, 0, 0));
+    }
+
+    #[test]
+    fn backoff_is_clamped() {
+        assert_eq!(retry_backoff(100, 1), 100);
+        assert_eq!(retry_backoff(100, 3), 400);
+        assert_eq!(retry_backoff(100, 64), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(100, 200), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(u64::MAX, 2), MAX_RETRY_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let broken = StandIn::start(|_, _| (503, String::new())).await;
+        let d = downloader(vec![source("broken", &broken, 2, 1)], 3);
+
+        let failed = d
+            .download(1, None, &temp_dir("give_up"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(failed.error, GetBeatmapError::Unavailable);
+        assert_eq!(broken.count(), 3);
+    }
+
+    #[tokio::test]
+    async fn skips_unhealthy_sources_during_cooldown() {
+        let broken = StandIn::start(|_, _| (500, String::new())).await;
+        let mirror = StandIn::start(|_, _| (200, osu_file(1, "Normal"))).await;
+        let d = downloader(
+            vec![
+                source("broken", &broken, 0, 0),
+                source("mirror", &mirror, 0, 0),
+            ],
+            2,
+        );
+        let dir = temp_dir("cooldown");
+
+        for _ in 0..2 {
+            assert!(d.download(1, None, &dir).await.is_ok());
+        }
+        assert_eq!(broken.count(), 2);
+        assert!(!BeatmapDownloader::is_healthy(&d.sources[0].1));
+        assert_eq!(d.health()[0]["healthy"], false);
+
+        // Cooling down, not requested again
+        assert!(d.download(1, None, &dir).await.is_ok());
+        assert_eq!(broken.count(), 2);
+        assert_eq!(mirror.count(), 3);
+    }
+
+    #[tokio::test]
+    async fn tries_all_sources_when_all_unhealthy() {
+        let broken = StandIn::start(|_, _| (500, String::new())).await;
+        let d = downloader(vec![source("broken", &broken, 0, 0)], 1);
+        let dir = temp_dir("all_unhealthy");
+
+        assert!(d.download(1, None, &dir).await.is_err());
+        assert!(!BeatmapDownloader::is_healthy(&d.sources[0].1));
+        assert!(d.download(1, None, &dir).await.is_err());
+        assert_eq!(broken.count(), 2);
+    }
+
+    #[tokio::test]
+    async fn not_found_is_not_a_failure() {
+        let missing = StandIn::start(|_, _| (404, String::new())).await;
+        // osu! answers 200 with an empty body for unknown beatmaps
+        let empty = StandIn::start(|_, _| (200, String::new())).await;
+        let d = downloader(
+            vec![
+                source("missing", &missing, 3, 1),
+                source("empty", &empty, 3, 1),
+            ],
+            1,
+        );
+
+        let failed = d
+            .download(1, None, &temp_dir("not_found"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(failed.error, GetBeatmapError::NotFound);
+        // No retry on 404
+        assert_eq!(missing.count(), 1);
+        assert_eq!(empty.count(), 1);
+        assert_eq!(health_of(&d, 0), (1, 0, 0));
+        assert_eq!(health_of(&d, 1), (1, 0, 0));
+        assert!(BeatmapDownloader::is_healthy(&d.sources[0].1));
+    }
+
+    #[tokio::test]
+    async fn server_error_is_unavailable() {
+        let missing = StandIn::start(|_, _| (404, String::new())).await;
+        let broken = StandIn::start(|_, _| (500, String::new())).await;
+        let d = downloader(
+            vec![
+                source("missing", &missing, 0, 0),
+                source("broken", &broken, 0, 0),
+            ],
+            3,
+        );
+
+        let failed = d
+            .download(1, None, &temp_dir("unavailable"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(failed.error, GetBeatmapError::Unavailable);
+        assert_eq!(health_of(&d, 1), (0, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn keeps_other_version_on_md5_mismatch() {
+        let mirror = StandIn::start(|_, _| (200, osu_file(1, "Updated"))).await;
+        let d = downloader(vec![source("mirror", &mirror, 0, 0)], 3);
+        let dir = temp_dir("md5_mismatch");
+        let request_md5 = osu_files::bytes_md5(osu_file(1, "Old").as_bytes());
+
+        let failed = d.download(1, Some(&request_md5), &dir).await.err().unwrap();
+        assert_eq!(failed.error, GetBeatmapError::NotFound);
+        let other = failed.other_version.unwrap();
+        assert_eq!(
+            other.md5,
+            osu_files::bytes_md5(osu_file(1, "Updated").as_bytes())
+        );
+        assert_eq!(health_of(&d, 0), (1, 0, 0));
+        assert!(!std::path::Path::new(&format!("{}/{}", dir, osu_files::QUARANTINE_DIR)).exists());
+    }
+
+    #[tokio::test]
+    async fn quarantines_unparsable_files() {
+        let garbage = StandIn::start(|_, _| (200, "<html>maintenance</html>".to_string())).await;
+        let mirror = StandIn::start(|_, _| (200, osu_file(1, "Normal"))).await;
+        let d = downloader(
+            vec![
+                source("garbage", &garbage, 0, 0),
+                source("mirror", &mirror, 0, 0),
+            ],
+            3,
+        );
+        let dir = temp_dir("quarantine");
+
+        assert!(d.download(1, None, &dir).await.is_ok());
+        assert_eq!(health_of(&d, 0), (0, 1, 1));
+        let quarantined = std::fs::read_dir(format!("{}/{}", dir, osu_files::QUARANTINE_DIR))
+            .unwrap()
+            .count();
+        // The file and its .json
+        assert_eq!(quarantined, 2);
+    }
+}
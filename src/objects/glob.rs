@@ -3,8 +3,17 @@ use tokio::sync::RwLock;
 
 use ntex::web::types::Data;
 use peace_objects::osu_api::OsuApi;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use super::Caches;
+use super::{
+    algorithm::AlgorithmRegistry,
+    beatmap_index::BeatmapIndex,
+    downloader::BeatmapDownloader,
+    osu_api_v2::OsuApiV2,
+    prefetcher::Prefetcher,
+    rate_limiter::{self, KeyedRateLimiter},
+    Caches,
+};
 use crate::renders::MainPage;
 use crate::settings::LocalConfig;
 
@@ -18,15 +27,21 @@ use peace_settings::bancho::BanchoConfig;
 use peace_utils::web::lock_wrapper as lw;
 
 pub struct Glob {
-    #[cfg(feature = "with_peace")]
-    pub osu_api: Data<RwLock<OsuApi>>,
-    #[cfg(not(feature = "with_peace"))]
-    pub osu_api: Data<OsuApi>,
+    pub osu_api_v2: Data<OsuApiV2>,
+    /// One osu!api v1 client for each key, rotated by the rate limiter
+    pub osu_api_limiter: Data<KeyedRateLimiter<OsuApi>>,
+    pub osu_api_v2_limiter: Data<KeyedRateLimiter<()>>,
 
     #[cfg(feature = "with_peace")]
     pub peace_api: Data<PeaceApi>,
 
     pub caches: Data<Caches>,
+    /// Offline mode, never fetch beatmaps from outside
+    pub offline: AtomicBool,
+    pub beatmap_index: Data<BeatmapIndex>,
+    pub downloader: Data<BeatmapDownloader>,
+    pub prefetcher: Data<Prefetcher>,
+    pub algorithms: Data<AlgorithmRegistry>,
     pub render_main_page: Data<MainPage>,
     pub local_config: LocalConfig,
 
@@ -45,7 +60,7 @@ impl Glob {
         #[cfg(feature = "with_peace")]
         let cfg = BanchoConfig::create(&database).await.unwrap();
         #[cfg(feature = "with_peace")]
-        let osu_api = lw(OsuApi::new(cfg.data.server.osu_api_keys.clone()).await);
+        let osu_api_keys = cfg.data.server.osu_api_keys.clone();
         #[cfg(feature = "with_peace")]
         let config = lw(cfg);
         #[cfg(feature = "with_peace")]
@@ -55,22 +70,72 @@ impl Glob {
         ));
 
         #[cfg(not(feature = "with_peace"))]
-        let osu_api = Data::new(OsuApi::new(local_config.data.osu_api_keys.clone()).await);
+        let osu_api_keys = local_config.data.osu_api_keys.clone();
+
+        let osu_api_v2 = Data::new(OsuApiV2::new(&local_config.data.osu_api.v2));
+
+        // Rate limiters
+        let rate_limit = &local_config.data.osu_api.rate_limit;
+        let counter = rate_limiter::osu_api_requests_counter(&local_config.data.prom.namespace);
+        let mut key_apis = Vec::with_capacity(osu_api_keys.len());
+        for key in osu_api_keys {
+            key_apis.push(OsuApi::new(vec![key]).await);
+        }
+        if key_apis.is_empty() {
+            key_apis.push(OsuApi::new(Vec::new()).await);
+        };
+        let osu_api_limiter = Data::new(KeyedRateLimiter::new(
+            "osu_api_v1",
+            key_apis,
+            rate_limit,
+            counter.clone(),
+        ));
+        let osu_api_v2_limiter = Data::new(KeyedRateLimiter::new(
+            "osu_api_v2",
+            vec![()],
+            rate_limit,
+            counter,
+        ));
 
         let render_main_page = Data::new(MainPage::new());
         let caches = Data::new(Caches::new(local_config.data.clone()));
+        let beatmap_index = Data::new(BeatmapIndex::load(
+            &local_config.data.beatmap_index_file,
+            local_config.data.beatmap_cache_timeout,
+        ));
+        let downloader = Data::new(BeatmapDownloader::new(&local_config.data.beatmap_download));
 
         Glob {
-            osu_api,
+            osu_api_v2,
+            osu_api_limiter,
+            osu_api_v2_limiter,
             #[cfg(feature = "with_peace")]
             database: Data::new(database.clone()),
             #[cfg(feature = "with_peace")]
             peace_api,
             caches,
+            offline: AtomicBool::new(local_config.data.offline),
+            beatmap_index,
+            downloader,
+            prefetcher: Data::new(Prefetcher::new()),
+            algorithms: Data::new(AlgorithmRegistry::new(
+                &local_config.data.algorithms,
+                &local_config.data.osu_files_dir,
+            )),
             render_main_page,
             #[cfg(feature = "with_peace")]
             config,
             local_config: local_config.clone(),
         }
     }
+
+    #[inline(always)]
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::SeqCst)
+    }
+
+    #[inline(always)]
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::SeqCst)
+    }
 }
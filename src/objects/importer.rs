@@ -0,0 +1,284 @@
+use {
+    bytes::Bytes,
+    colored::Colorize,
+    std::{
+        collections::HashSet,
+        io::{Cursor, Read},
+        path::{Path, PathBuf},
+        time::Instant,
+    },
+};
+
+use crate::objects::{
+    beatmap_index::BeatmapIndex,
+    osu_files::{self, OsuFileMetadata},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportResult {
+    Imported(String),
+    Duplicate(String),
+    Invalid(String),
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub files: usize,
+    pub archives: usize,
+    pub imported: usize,
+    pub duplicates: usize,
+    /// (source, reason)
+    pub invalid: Vec<(String, String)>,
+}
+
+impl ImportReport {
+    #[inline(always)]
+    pub fn add(&mut self, source: String, result: &ImportResult) {
+        self.files += 1;
+        match result {
+            ImportResult::Imported(_) => self.imported += 1,
+            ImportResult::Duplicate(_) => self.duplicates += 1,
+            ImportResult::Invalid(reason) => self.invalid.push((source, reason.clone())),
+        }
+    }
+
+    pub fn print(&self, start: Instant) {
+        for (source, reason) in self.invalid.iter() {
+            println!("{}", format!("  [Invalid] {}: {}", source, reason).red());
+        }
+        println!(
+            "\n{}\n",
+            format!(
+                "> Import done, \n> Files: {}, Archives: {}; \n> Imported: {}, Duplicates: {}, Invalid: {}; \n> time spent: {:?}",
+                self.files,
+                self.archives,
+                self.imported,
+                self.duplicates,
+                self.invalid.len(),
+                start.elapsed()
+            )
+            .bright_yellow()
+        )
+    }
+}
+
+/// Validate one .osu file, and store it as `<md5>.osu` (if not exists),
+/// then index it with its metadata and the bid / sid we were given.
+pub async fn import_osu_bytes(
+    bytes: &Bytes,
+    osu_files_dir: &String,
+    index: &BeatmapIndex,
+    bid: Option<i32>,
+    sid: Option<i32>,
+) -> ImportResult {
+    let md5 = match osu_files::verify_osu_file(bytes, None).await {
+        Ok((_, md5)) => md5,
+        Err(err) => return ImportResult::Invalid(err.reason()),
+    };
+    let metadata = OsuFileMetadata::from_bytes(bytes);
+    let exists = Path::new(&format!("{}/{}.osu", osu_files_dir, md5)).exists();
+    if !exists && !osu_files::write_osu_file_atomic(bytes, osu_files_dir, &md5).await {
+        return ImportResult::Invalid("failed to write .osu file".to_string());
+    };
+    index.insert_metadata(&md5, &metadata, bid, sid).await;
+    if exists {
+        ImportResult::Duplicate(md5)
+    } else {
+        ImportResult::Imported(md5)
+    }
+}
+
+/// Largest .osu file we read from a .osz archive (bytes)
+pub const MAX_OSZ_ENTRY_SIZE: u64 = 16 * 1024 * 1024;
+/// Largest sum of .osu files we read from a .osz archive (bytes)
+pub const MAX_OSZ_TOTAL_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Read all .osu files in a .osz (zip) archive
+#[inline(always)]
+pub fn read_osz(bytes: &[u8]) -> Result<Vec<(String, Bytes)>, String> {
+    read_osz_limited(bytes, MAX_OSZ_ENTRY_SIZE, MAX_OSZ_TOTAL_SIZE)
+}
+
+/// The declared sizes in a zip can lie, so only ever read up to the limits
+pub fn read_osz_limited(
+    bytes: &[u8],
+    max_entry_size: u64,
+    max_total_size: u64,
+) -> Result<Vec<(String, Bytes)>, String> {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(bytes)).map_err(|err| format!("{:?}", err))?;
+    let mut files = Vec::new();
+    let mut total = 0;
+    for i in 0..archive.len() {
+        let file = archive.by_index(i).map_err(|err| format!("{:?}", err))?;
+        if !file.is_file() || !file.name().to_lowercase().ends_with(".osu") {
+            continue;
+        };
+        let name = file.name().to_string();
+        let limit = max_entry_size.min(max_total_size - total);
+        let mut buf = Vec::new();
+        file.take(limit + 1)
+            .read_to_end(&mut buf)
+            .map_err(|err| format!("{:?}", err))?;
+        if buf.len() as u64 > limit {
+            return Err(if limit < max_entry_size {
+                format!(
+                    "archive has more than {} bytes of .osu files",
+                    max_total_size
+                )
+            } else {
+                format!("'{}' is larger than {} bytes", name, max_entry_size)
+            });
+        };
+        total += buf.len() as u64;
+        files.push((name, Bytes::from(buf)));
+    }
+    Ok(files)
+}
+
+/// .osz files are zip archives
+#[inline(always)]
+pub fn is_osz(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"PK\x03\x04")
+}
+
+#[inline(always)]
+fn extension(path: &Path) -> Option<String> {
+    path.extension()?.to_str().map(|e| e.to_lowercase())
+}
+
+/// Walk the paths (osu! Songs folder, dirs of .osz, single files) recursively
+pub async fn import_paths(
+    paths: &Vec<PathBuf>,
+    osu_files_dir: &String,
+    index: &BeatmapIndex,
+) -> ImportReport {
+    let mut report = ImportReport::default();
+    let mut pending = paths.clone();
+    let target_dir = std::fs::canonicalize(osu_files_dir).ok();
+    // Symlinked dirs may point back to a parent, walk every real dir only once
+    let mut visited = HashSet::new();
+    while let Some(path) = pending.pop() {
+        if path.is_dir() {
+            let real_path = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            // Don't import osu_files_dir into itself
+            if Some(&real_path) == target_dir.as_ref() || !visited.insert(real_path) {
+                continue;
+            };
+            match std::fs::read_dir(&path) {
+                Ok(entries) => pending.extend(entries.filter_map(|e| e.ok()).map(|e| e.path())),
+                Err(err) => report
+                    .invalid
+                    .push((format!("{:?}", path), format!("{:?}", err))),
+            };
+            continue;
+        };
+        let ext = extension(&path);
+        if ext.as_deref() != Some("osu") && ext.as_deref() != Some("osz") {
+            continue;
+        };
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(b) => Bytes::from(b),
+            Err(err) => {
+                report
+                    .invalid
+                    .push((format!("{:?}", path), format!("{:?}", err)));
+                continue;
+            }
+        };
+        if ext.as_deref() == Some("osz") {
+            report.archives += 1;
+            match read_osz(&bytes) {
+                Ok(files) => {
+                    for (name, bytes) in files {
+                        let result =
+                            import_osu_bytes(&bytes, osu_files_dir, index, None, None).await;
+                        report.add(format!("{:?}/{}", path, name), &result);
+                    }
+                }
+                Err(err) => report.invalid.push((format!("{:?}", path), err)),
+            };
+        } else {
+            let result = import_osu_bytes(&bytes, osu_files_dir, index, None, None).await;
+            report.add(format!("{:?}", path), &result);
+        };
+    }
+    index.save().await;
+    report
+}
+
+/// Rename .osu files in osu_files_dir to their md5 without overwriting anything:
+/// a file is only removed after its content is stored as `<md5>.osu`.
+pub async fn recalculate_osu_files_md5(osu_files_dir: &String, index: &BeatmapIndex) {
+    println!("\n  Recalculating MD5 file names...");
+    let start = Instant::now();
+    let mut report = ImportReport::default();
+    let entries = match std::fs::read_dir(osu_files_dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            println!(
+                "{}",
+                format!("> [Error] Cannot read dir '{}': {:?}", osu_files_dir, err).red()
+            );
+            return;
+        }
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !path.is_file() || extension(&path).as_deref() != Some("osu") {
+            continue;
+        };
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(b) => Bytes::from(b),
+            Err(_) => continue,
+        };
+        // Already named as its md5
+        if path.file_stem().and_then(|s| s.to_str()) == Some(osu_files::bytes_md5(&bytes).as_str())
+        {
+            continue;
+        };
+        let result = import_osu_bytes(&bytes, osu_files_dir, index, None, None).await;
+        if !matches!(result, ImportResult::Invalid(_)) {
+            let _ = tokio::fs::remove_file(&path).await;
+        };
+        report.add(format!("{:?}", path), &result);
+    }
+    index.save().await;
+    report.print(start);
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::io::Write,
+        zip::{write::FileOptions, ZipWriter},
+    };
+
+    fn osz(files: &[(&str, usize)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, size) in files.iter() {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(&vec![b'0'; *size]).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn osz_skips_other_files() {
+        let bytes = osz(&[("a.osu", 10), ("bg.jpg", 1000), ("b.OSU", 20)]);
+        let files = read_osz_limited(&bytes, 100, 1000).unwrap();
+        let names: Vec<&str> = files.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["a.osu", "b.OSU"]);
+        assert_eq!(files[1].1.len(), 20);
+    }
+
+    #[test]
+    fn osz_limits_entry_and_total_size() {
+        assert!(read_osz_limited(&osz(&[("a.osu", 100)]), 100, 1000).is_ok());
+        let err = read_osz_limited(&osz(&[("a.osu", 101)]), 100, 1000).unwrap_err();
+        assert!(err.contains("'a.osu'"));
+        let err = read_osz_limited(&osz(&[("a.osu", 80), ("b.osu", 80)]), 100, 150).unwrap_err();
+        assert!(err.contains("150"));
+    }
+}
@@ -3,5 +3,23 @@ mod server;
 
 pub use caches::*;
 pub use server::PPserver;
+pub mod algorithm;
+pub mod beatmap_index;
+#[macro_use]
 pub mod calculator;
+pub mod downloader;
 pub mod glob;
+pub mod importer;
+pub mod osu_api_v2;
+pub mod osu_files;
+pub mod osu_strains;
+#[cfg(feature = "peace_performance_v0_3")]
+pub mod peace_performance_prev;
+pub mod plugins;
+pub mod prefetcher;
+pub mod profile;
+pub mod rate_limiter;
+pub mod watcher;
+
+#[cfg(test)]
+pub mod test_utils;
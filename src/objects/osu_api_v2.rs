@@ -0,0 +1,357 @@
+use {
+    chrono::Local,
+    serde::{de::DeserializeOwned, Deserialize},
+    std::time::Duration,
+    tokio::sync::RwLock,
+};
+
+use crate::objects::osu_files;
+use crate::settings::model::OsuApiV2Settings;
+
+/// Refresh the token a little earlier than it really expires
+const TOKEN_EXPIRE_MARGIN: i64 = 60;
+
+#[derive(Debug)]
+pub enum OsuApiV2Error {
+    Request(String),
+    Status(u16),
+    NotFound,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    access_token: String,
+    expires_at: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BeatmapV2 {
+    pub id: i32,
+    pub beatmapset_id: i32,
+    pub checksum: Option<String>,
+    pub version: String,
+    pub mode_int: Option<u8>,
+    pub beatmapset: Option<BeatmapsetV2>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BeatmapsetV2 {
+    pub id: i32,
+    pub artist: String,
+    pub title: String,
+    pub creator: String,
+    pub beatmaps: Option<Vec<BeatmapV2>>,
+}
+
+impl BeatmapsetV2 {
+    /// Find the difficulty whose .osu file name is `file_name`
+    #[inline(always)]
+    pub fn find_by_file_name(&self, file_name: &String) -> Option<&BeatmapV2> {
+        let target = osu_files::normalize_file_name(file_name);
+        self.beatmaps.as_ref()?.iter().find(|b| {
+            let name =
+                osu_files::osu_file_name(&self.artist, &self.title, &self.creator, &b.version);
+            osu_files::normalize_file_name(&name) == target
+        })
+    }
+}
+
+/// osu!api v2 client, authorized with client credentials OAuth
+pub struct OsuApiV2 {
+    pub client: reqwest::Client,
+    pub settings: OsuApiV2Settings,
+    token: RwLock<Option<Token>>,
+}
+
+impl OsuApiV2 {
+    pub fn new(settings: &OsuApiV2Settings) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            settings: settings.clone(),
+            token: RwLock::new(None),
+        }
+    }
+
+    async fn request_token(&self) -> Result<Token, OsuApiV2Error> {
+        let resp = self
+            .client
+            .post(&self.settings.token_url)
+            .timeout(Duration::from_secs(self.settings.timeout))
+            .form(&[
+                ("client_id", self.settings.client_id.to_string()),
+                ("client_secret", self.settings.client_secret.clone()),
+                ("grant_type", "client_credentials".to_string()),
+                ("scope", "public".to_string()),
+            ])
+            .send()
+            .await
+            .map_err(|err| OsuApiV2Error::Request(format!("{:?}", err)))?;
+        if !resp.status().is_success() {
+            return Err(OsuApiV2Error::Status(resp.status().as_u16()));
+        };
+        let t = resp
+            .json::<TokenResponse>()
+            .await
+            .map_err(|err| OsuApiV2Error::Request(format!("{:?}", err)))?;
+        debug!("[osu_api_v2] Token refreshed, expires in {}s", t.expires_in);
+        Ok(Token {
+            access_token: t.access_token,
+            expires_at: Local::now().timestamp() + t.expires_in,
+        })
+    }
+
+    /// Get a valid access token, refresh it if expired
+    async fn access_token(&self) -> Result<String, OsuApiV2Error> {
+        if let Some(token) = self.token.read().await.as_ref() {
+            if token.expires_at - TOKEN_EXPIRE_MARGIN > Local::now().timestamp() {
+                return Ok(token.access_token.clone());
+            }
+        };
+        let mut token = self.token.write().await;
+        // Someone may have refreshed it while we are waiting for the lock
+        if let Some(t) = token.as_ref() {
+            if t.expires_at - TOKEN_EXPIRE_MARGIN > Local::now().timestamp() {
+                return Ok(t.access_token.clone());
+            }
+        };
+        let t = self.request_token().await?;
+        let access_token = t.access_token.clone();
+        *token = Some(t);
+        Ok(access_token)
+    }
+
+    pub async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, OsuApiV2Error> {
+        let url = format!("{}{}", self.settings.base_url, path);
+        // Retry once with a new token if the old one was revoked
+        for retry in 0..2 {
+            let resp = self
+                .client
+                .get(&url)
+                .timeout(Duration::from_secs(self.settings.timeout))
+                .bearer_auth(self.access_token().await?)
+                .query(query)
+                .send()
+                .await
+                .map_err(|err| OsuApiV2Error::Request(format!("{:?}", err)))?;
+            match resp.status().as_u16() {
+                401 if retry == 0 => {
+                    *self.token.write().await = None;
+                    continue;
+                }
+                404 => return Err(OsuApiV2Error::NotFound),
+                s if !resp.status().is_success() => return Err(OsuApiV2Error::Status(s)),
+                _ => {
+                    return resp
+                        .json::<T>()
+                        .await
+                        .map_err(|err| OsuApiV2Error::Request(format!("{:?}", err)))
+                }
+            }
+        }
+        Err(OsuApiV2Error::Status(401))
+    }
+
+    #[inline(always)]
+    pub async fn beatmap_by_md5(&self, md5: &String) -> Result<BeatmapV2, OsuApiV2Error> {
+        self.get("/beatmaps/lookup", &[("checksum", md5.clone())])
+            .await
+    }
+
+    #[inline(always)]
+    pub async fn beatmap_by_bid(&self, bid: i32) -> Result<BeatmapV2, OsuApiV2Error> {
+        self.get(&format!("/beatmaps/{}", bid), &[]).await
+    }
+
+    #[inline(always)]
+    pub async fn beatmapset_by_sid(&self, sid: i32) -> Result<BeatmapsetV2, OsuApiV2Error> {
+        self.get(&format!("/beatmapsets/{}", sid), &[]).await
+    }
+
+    /// Resolve beatmap id with bid (checks that it exists), md5, or sid + file name
+    pub async fn resolve_bid(
+        &self,
+        md5: Option<&String>,
+        bid: Option<i32>,
+        sid: Option<i32>,
+        file_name: Option<&String>,
+    ) -> Result<i32, OsuApiV2Error> {
+        // The bid still points to the current version, even if the md5 is outdated
+        if let Some(bid) = bid {
+            return Ok(self.beatmap_by_bid(bid).await?.id);
+        };
+        if let Some(md5) = md5 {
+            return Ok(self.beatmap_by_md5(md5).await?.id);
+        };
+        if let (Some(sid), Some(file_name)) = (sid, file_name) {
+            return self
+                .beatmapset_by_sid(sid)
+                .await?
+                .find_by_file_name(file_name)
+                .map(|b| b.id)
+                .ok_or(OsuApiV2Error::NotFound);
+        };
+        Err(OsuApiV2Error::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::test_utils::StandIn;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    const BEATMAP: &str = r#"{"id": 75, "beatmapset_id": 1, "checksum": "a5b99395a42bd55bc5eb1d2411cbdf8b", "version": "Normal", "mode_int": 0}"#;
+    const BEATMAPSET: &str = r#"{"id": 1, "artist": "Kenji Ninuma", "title": "DISCO★PRINCE", "creator": "peppy", "beatmaps": [
+        {"id": 75, "beatmapset_id": 1, "checksum": null, "version": "Normal", "mode_int": 0}
+    ]}"#;
+
+    /// osu! stand-in: token endpoint gives `t0`, `t1`...; `valid_from` is the first accepted token
+    async fn osu(valid_from: usize) -> (StandIn, OsuApiV2) {
+        let tokens = Arc::new(AtomicUsize::new(0));
+        let stand_in = StandIn::start(move |req, _| {
+            if req.path == "/oauth/token" {
+                let n = tokens.fetch_add(1, Ordering::SeqCst);
+                return (
+                    200,
+                    format!(r#"{{"access_token": "t{}", "expires_in": 86400}}"#, n),
+                );
+            };
+            let token = req
+                .header("authorization")
+                .and_then(|h| h.strip_prefix("Bearer t"))
+                .and_then(|n| n.parse::<usize>().ok());
+            if token.map_or(true, |n| n < valid_from) {
+                return (401, String::new());
+            };
+            match req.path.as_str() {
+                "/api/v2/beatmaps/75" => (200, BEATMAP.to_string()),
+                "/api/v2/beatmaps/lookup?checksum=a5b99395a42bd55bc5eb1d2411cbdf8b" => {
+                    (200, BEATMAP.to_string())
+                }
+                "/api/v2/beatmapsets/1" => (200, BEATMAPSET.to_string()),
+                _ => (404, r#"{"error": null}"#.to_string()),
+            }
+        })
+        .await;
+        let api = OsuApiV2::new(&OsuApiV2Settings {
+            client_id: 1,
+            client_secret: "secret".to_string(),
+            base_url: format!("{}/api/v2", stand_in.url),
+            token_url: format!("{}/oauth/token", stand_in.url),
+            timeout: 5,
+        });
+        (stand_in, api)
+    }
+
+    fn token_requests(stand_in: &StandIn) -> usize {
+        stand_in
+            .requests()
+            .iter()
+            .filter(|r| r.path == "/oauth/token")
+            .count()
+    }
+
+    #[tokio::test]
+    async fn fetches_token_once() {
+        let (stand_in, api) = osu(0).await;
+
+        assert_eq!(api.beatmap_by_bid(75).await.unwrap().id, 75);
+        assert_eq!(api.beatmap_by_bid(75).await.unwrap().id, 75);
+        assert_eq!(token_requests(&stand_in), 1);
+
+        let token = &stand_in.requests()[0];
+        assert_eq!(token.method, "POST");
+        assert!(token.body.contains("grant_type=client_credentials"));
+        assert!(token.body.contains("client_id=1"));
+        assert_eq!(
+            stand_in.requests()[1].header("authorization"),
+            Some("Bearer t0")
+        );
+    }
+
+    #[tokio::test]
+    async fn refreshes_token_after_401() {
+        let (stand_in, api) = osu(1).await;
+
+        let md5 = "a5b99395a42bd55bc5eb1d2411cbdf8b".to_string();
+        let beatmap = api.beatmap_by_md5(&md5).await.unwrap();
+        assert_eq!(beatmap.checksum, Some(md5));
+        assert_eq!(token_requests(&stand_in), 2);
+        assert_eq!(
+            stand_in.requests().last().unwrap().header("authorization"),
+            Some("Bearer t1")
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_second_401() {
+        let (stand_in, api) = osu(usize::MAX).await;
+
+        assert!(matches!(
+            api.beatmap_by_bid(75).await,
+            Err(OsuApiV2Error::Status(401))
+        ));
+        assert_eq!(token_requests(&stand_in), 2);
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        let (_stand_in, api) = osu(0).await;
+
+        assert!(matches!(
+            api.beatmap_by_bid(1).await,
+            Err(OsuApiV2Error::NotFound)
+        ));
+        assert!(matches!(
+            api.beatmap_by_md5(&"0".repeat(32)).await,
+            Err(OsuApiV2Error::NotFound)
+        ));
+        assert!(matches!(
+            api.resolve_bid(None, None, Some(1), Some(&"unknown.osu".to_string()))
+                .await,
+            Err(OsuApiV2Error::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn resolves_bid() {
+        let (_stand_in, api) = osu(0).await;
+        let md5 = "a5b99395a42bd55bc5eb1d2411cbdf8b".to_string();
+        let file_name = "Kenji Ninuma - DISCOPRINCE (peppy) [Normal].osu".to_string();
+
+        assert_eq!(
+            api.resolve_bid(None, Some(75), None, None).await.unwrap(),
+            75
+        );
+        assert_eq!(
+            api.resolve_bid(Some(&md5), None, None, None).await.unwrap(),
+            75
+        );
+        assert_eq!(
+            api.resolve_bid(None, None, Some(1), Some(&file_name))
+                .await
+                .unwrap(),
+            75
+        );
+        // An outdated md5 does not matter if the bid is known
+        assert_eq!(
+            api.resolve_bid(Some(&"0".repeat(32)), Some(75), None, None)
+                .await
+                .unwrap(),
+            75
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use {bytes::Bytes, chrono::Local, peace_performance::Beatmap as PPbeatmap, serde_json::json};
+
+/// Sub-dir of `osu_files_dir` where rejected downloads are kept for inspection
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    Md5Mismatch { expected: String, actual: String },
+    ParseError(String),
+    EmptyHitObjects,
+}
+
+impl VerifyError {
+    #[inline(always)]
+    pub fn reason(&self) -> String {
+        match self {
+            Self::Md5Mismatch { expected, actual } => {
+                format!("md5 mismatch, expected: {}, actual: {}", expected, actual)
+            }
+            Self::ParseError(err) => format!("cannot parse .osu file, err: {}", err),
+            Self::EmptyHitObjects => "beatmap has no hit objects".to_string(),
+        }
+    }
+}
+
+/// The file name osu! gives a difficulty: `Artist - Title (Creator) [Version].osu`
+#[inline(always)]
+pub fn osu_file_name(artist: &str, title: &str, creator: &str, version: &str) -> String {
+    format!("{} - {} ({}) [{}].osu", artist, title, creator, version)
+}
+
+/// osu! strips characters that are invalid in file names,
+/// so only compare letters and digits
+#[inline(always)]
+pub fn normalize_file_name(file_name: &str) -> String {
+    file_name
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+#[inline(always)]
+pub fn bytes_md5(bytes: &[u8]) -> String {
+    format!("{:x}", md5::compute(bytes))
+}
+
+/// Check a downloaded .osu file before it touches the disk or the caches:
+/// the content md5 must match (if we know it), it must parse,
+/// and it must contain at least one hit object.
+#[inline(always)]
+pub async fn verify_osu_file(
+    bytes: &Bytes,
+    expected_md5: Option<&String>,
+) -> Result<(PPbeatmap, String), VerifyError> {
+    let md5 = bytes_md5(bytes);
+    if let Some(expected) = expected_md5 {
+        if expected != &md5 {
+            return Err(VerifyError::Md5Mismatch {
+                expected: expected.clone(),
+                actual: md5,
+            });
+        }
+    };
+
+    let beatmap = PPbeatmap::parse(bytes.as_ref())
+        .await
+        .map_err(|err| VerifyError::ParseError(format!("{:?}", err)))?;
+    if beatmap.hit_objects.is_empty() {
+        return Err(VerifyError::EmptyHitObjects);
+    };
+
+    Ok((beatmap, md5))
+}
+
+/// Write to a temp file next to the target first, then rename it into place,
+/// so a crash or a full disk can never leave a truncated `<md5>.osu` behind.
+#[inline(always)]
+pub async fn write_osu_file_atomic(bytes: &Bytes, dir: &String, md5: &String) -> bool {
+    let path = format!("{}/{}.osu", dir, md5);
+    let tmp_path = format!("{}.tmp", path);
+    if let Err(err) = tokio::fs::write(&tmp_path, bytes).await {
+        warn!(
+            "[osu_files] Failed to write temp .osu file '{}', err: {:?}",
+            tmp_path, err
+        );
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return false;
+    };
+    if let Err(err) = tokio::fs::rename(&tmp_path, &path).await {
+        warn!(
+            "[osu_files] Failed to move temp .osu file into '{}', err: {:?}",
+            path, err
+        );
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return false;
+    };
+    true
+}
+
+/// Keep a rejected download in `<osu_files_dir>/quarantine`,
+/// with a `.json` file beside it recording why it was rejected.
+#[inline(always)]
+pub async fn quarantine_osu_file(
+    bytes: &Bytes,
+    dir: &String,
+    bid: i32,
+    request_md5: Option<&String>,
+    reason: &VerifyError,
+) -> bool {
+    let quarantine_dir = format!("{}/{}", dir, QUARANTINE_DIR);
+    if let Err(err) = tokio::fs::create_dir_all(&quarantine_dir).await {
+        warn!(
+            "[osu_files] Failed to create quarantine dir '{}', err: {:?}",
+            quarantine_dir, err
+        );
+        return false;
+    };
+
+    let now = Local::now();
+    let name = format!("{}/{}_{}", quarantine_dir, bid, now.timestamp_millis());
+    let info = json!({
+        "bid": bid,
+        "request_md5": request_md5,
+        "content_md5": bytes_md5(bytes),
+        "size": bytes.len(),
+        "reason": reason.reason(),
+        "time": now.to_rfc3339(),
+    });
+
+    let written = tokio::fs::write(format!("{}.osu", name), bytes)
+        .await
+        .is_ok()
+        && tokio::fs::write(format!("{}.json", name), info.to_string())
+            .await
+            .is_ok();
+    if !written {
+        warn!("[osu_files] Failed to quarantine .osu file '{}'", name);
+    };
+    written
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OsuFileMetadata {
+    pub artist: String,
+    pub title: String,
+    pub creator: String,
+    pub version: String,
+    pub bid: Option<i32>,
+    pub sid: Option<i32>,
+}
+
+impl OsuFileMetadata {
+    /// Read the `[Metadata]` section of a .osu file
+    pub fn parse(content: &str) -> Self {
+        let mut m = Self::default();
+        let mut in_metadata = false;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.starts_with('[') {
+                if in_metadata {
+                    break;
+                };
+                in_metadata = line == "[Metadata]";
+                continue;
+            };
+            if !in_metadata {
+                continue;
+            };
+            let (key, value) = match line.find(':') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => continue,
+            };
+            match key {
+                "Artist" => m.artist = value.to_string(),
+                "Title" => m.title = value.to_string(),
+                "Creator" => m.creator = value.to_string(),
+                "Version" => m.version = value.to_string(),
+                // Old .osu files may have 0 or -1 here
+                "BeatmapID" => m.bid = value.parse().ok().filter(|i| *i > 0),
+                "BeatmapSetID" => m.sid = value.parse().ok().filter(|i| *i > 0),
+                _ => {}
+            }
+        }
+        m
+    }
+
+    #[inline(always)]
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self::parse(&String::from_utf8_lossy(bytes))
+    }
+
+    #[inline(always)]
+    pub fn file_name(&self) -> String {
+        osu_file_name(&self.artist, &self.title, &self.creator, &self.version)
+    }
+}
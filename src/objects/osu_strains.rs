@@ -0,0 +1,209 @@
+use peace_performance::{Beatmap as PPbeatmap, HitObject, HitObjectKind};
+
+use crate::objects::calculator::mods_clock_rate;
+
+// osu!std difficulty (ppv2 2021), the skills are private in peace-performance
+const OBJECT_RADIUS: f32 = 64.0;
+const NORMALIZED_RADIUS: f32 = 52.0;
+const CIRCLE_SIZE_BUFF_THRESHOLD: f32 = 30.0;
+const MIN_STRAIN_TIME: f32 = 50.0;
+
+const AIM_SKILL_MULTIPLIER: f32 = 26.25;
+const AIM_STRAIN_DECAY_BASE: f32 = 0.15;
+const AIM_ANGLE_BONUS_BEGIN: f32 = std::f32::consts::FRAC_PI_3;
+const AIM_TIMING_THRESHOLD: f32 = 107.0;
+
+const SPEED_SKILL_MULTIPLIER: f32 = 1400.0;
+const SPEED_STRAIN_DECAY_BASE: f32 = 0.3;
+const SPEED_ANGLE_BONUS_BEGIN: f32 = 5.0 * std::f32::consts::FRAC_PI_6;
+const SINGLE_SPACING_THRESHOLD: f32 = 125.0;
+const MIN_SPEED_BONUS: f32 = 75.0;
+const MAX_SPEED_BONUS: f32 = 45.0;
+const SPEED_BALANCING_FACTOR: f32 = 40.0;
+
+/// Strain peaks of the two osu!std skills, section by section
+#[derive(Debug, Default)]
+pub struct OsuStrains {
+    pub aim: Vec<f32>,
+    pub speed: Vec<f32>,
+}
+
+struct DifficultyObject {
+    /// Game time (ms)
+    delta: f32,
+    strain_time: f32,
+    jump: f32,
+    angle: Option<f32>,
+    spinner: bool,
+}
+
+struct Skill {
+    multiplier: f32,
+    decay_base: f32,
+    current_strain: f32,
+    current_peak: f32,
+    peaks: Vec<f32>,
+}
+
+impl Skill {
+    #[inline(always)]
+    fn new(multiplier: f32, decay_base: f32) -> Self {
+        Self {
+            multiplier,
+            decay_base,
+            current_strain: 1.0,
+            current_peak: 0.0,
+            peaks: Vec::new(),
+        }
+    }
+
+    #[inline(always)]
+    fn decay(&self, ms: f32) -> f32 {
+        self.decay_base.powf(ms / 1000.0)
+    }
+
+    #[inline(always)]
+    fn process(&mut self, delta: f32, value: f32) {
+        self.current_strain *= self.decay(delta);
+        self.current_strain += value * self.multiplier;
+        self.current_peak = self.current_peak.max(self.current_strain);
+    }
+
+    /// `since_prev`: game time from the previous object to the section start
+    #[inline(always)]
+    fn next_section(&mut self, since_prev: f32) {
+        self.peaks.push(self.current_peak);
+        self.current_peak = self.current_strain * self.decay(since_prev);
+    }
+}
+
+fn aim_value(curr: &DifficultyObject, prev: Option<&DifficultyObject>) -> f32 {
+    if curr.spinner {
+        return 0.0;
+    };
+    let mut result = 0.0;
+    if let (Some(prev), Some(angle)) = (prev, curr.angle) {
+        if angle > AIM_ANGLE_BONUS_BEGIN {
+            let scale = 90.0;
+            let angle_bonus = ((prev.jump - scale).max(0.0)
+                * (angle - AIM_ANGLE_BONUS_BEGIN).sin().powi(2)
+                * (curr.jump - scale).max(0.0))
+            .sqrt();
+            result = 1.5 * angle_bonus.powf(0.99) / prev.strain_time.max(AIM_TIMING_THRESHOLD);
+        };
+    };
+    let jump = curr.jump.powf(0.99);
+    (result + jump / curr.strain_time.max(AIM_TIMING_THRESHOLD)).max(jump / curr.strain_time)
+}
+
+fn speed_value(curr: &DifficultyObject) -> f32 {
+    if curr.spinner {
+        return 0.0;
+    };
+    let distance = curr.jump.min(SINGLE_SPACING_THRESHOLD);
+    let delta_time = curr.strain_time.max(MAX_SPEED_BONUS);
+    let speed_bonus = if delta_time < MIN_SPEED_BONUS {
+        1.0 + ((MIN_SPEED_BONUS - delta_time) / SPEED_BALANCING_FACTOR).powi(2)
+    } else {
+        1.0
+    };
+
+    let mut angle_bonus = 1.0;
+    if let Some(angle) = curr.angle.filter(|a| *a < SPEED_ANGLE_BONUS_BEGIN) {
+        angle_bonus = 1.0 + (1.5 * (SPEED_ANGLE_BONUS_BEGIN - angle)).sin().powi(2) / 3.57;
+        if angle < std::f32::consts::FRAC_PI_2 {
+            angle_bonus = 1.28;
+            if distance < 90.0 {
+                let bonus = (1.0 - angle_bonus) * ((90.0 - distance) / 10.0).min(1.0);
+                angle_bonus += if angle < std::f32::consts::FRAC_PI_4 {
+                    bonus
+                } else {
+                    bonus
+                        * ((std::f32::consts::FRAC_PI_2 - angle) / std::f32::consts::FRAC_PI_4)
+                            .sin()
+                };
+            };
+        };
+    };
+
+    (1.0 + (speed_bonus - 1.0) * 0.75)
+        * angle_bonus
+        * (0.95 + speed_bonus * (distance / SINGLE_SPACING_THRESHOLD).powf(3.5))
+        / curr.strain_time
+}
+
+/// Angle at `b` between `a` and `c`
+#[inline(always)]
+fn angle(a: &HitObject, b: &HitObject, c: &HitObject) -> f32 {
+    let (x1, y1) = (a.pos.x - b.pos.x, a.pos.y - b.pos.y);
+    let (x2, y2) = (c.pos.x - b.pos.x, c.pos.y - b.pos.y);
+    (x1 * y2 - y1 * x2).abs().atan2(x1 * x2 + y1 * y2).abs()
+}
+
+/// Aim and speed strain peaks of an osu!std map, sections of `section_length` (map time)
+/// aligned like the calculator's combined strains.
+///
+/// Jumps are measured between object (slider head) positions, slider paths and stacking
+/// are not followed, so aim + speed can be a bit below the calculator's combined strain.
+pub fn osu_strains(beatmap: &PPbeatmap, mods: u32, section_length: f32) -> OsuStrains {
+    let objects = &beatmap.hit_objects;
+    let first = match objects.first() {
+        Some(h) => h,
+        None => return OsuStrains::default(),
+    };
+    let clock_rate = mods_clock_rate(mods);
+    let cs = if mods & 16 > 0 {
+        (beatmap.cs * 1.3).min(10.0)
+    } else if mods & 2 > 0 {
+        beatmap.cs * 0.5
+    } else {
+        beatmap.cs
+    };
+    let radius = OBJECT_RADIUS * (1.0 - 0.7 * (cs - 5.0) / 5.0) / 2.0;
+    let mut scale = NORMALIZED_RADIUS / radius;
+    if radius < CIRCLE_SIZE_BUFF_THRESHOLD {
+        scale *= 1.0 + (CIRCLE_SIZE_BUFF_THRESHOLD - radius).min(5.0) / 50.0;
+    };
+
+    let mut aim = Skill::new(AIM_SKILL_MULTIPLIER, AIM_STRAIN_DECAY_BASE);
+    let mut speed = Skill::new(SPEED_SKILL_MULTIPLIER, SPEED_STRAIN_DECAY_BASE);
+    let mut section_end = (first.start_time / section_length).ceil() * section_length;
+    let mut prev: Option<DifficultyObject> = None;
+    for i in 1..objects.len() {
+        let (h, last) = (&objects[i], &objects[i - 1]);
+        while h.start_time > section_end {
+            let since_prev = (section_end - last.start_time) / clock_rate;
+            aim.next_section(since_prev);
+            speed.next_section(since_prev);
+            section_end += section_length;
+        }
+
+        let delta = (h.start_time - last.start_time) / clock_rate;
+        let spinner = matches!(h.kind, HitObjectKind::Spinner { .. });
+        let curr = DifficultyObject {
+            delta,
+            strain_time: delta.max(MIN_STRAIN_TIME),
+            jump: if spinner {
+                0.0
+            } else {
+                scale * ((h.pos.x - last.pos.x).powi(2) + (h.pos.y - last.pos.y).powi(2)).sqrt()
+            },
+            angle: if i > 1 {
+                Some(angle(&objects[i - 2], last, h))
+            } else {
+                None
+            },
+            spinner,
+        };
+        aim.process(curr.delta, aim_value(&curr, prev.as_ref()));
+        speed.process(curr.delta, speed_value(&curr));
+        prev = Some(curr);
+    }
+    aim.peaks.push(aim.current_peak);
+    speed.peaks.push(speed.current_peak);
+
+    OsuStrains {
+        aim: aim.peaks,
+        speed: speed.peaks,
+    }
+}
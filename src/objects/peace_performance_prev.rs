@@ -0,0 +1,103 @@
+use {
+    async_trait::async_trait,
+    peace_performance::{Beatmap as PPbeatmap, PpResult, StarResult},
+    peace_performance_v0_3 as v0_3,
+};
+
+use crate::objects::{
+    algorithm::PPAlgorithm,
+    calculator::{self, CalcData},
+};
+
+pub const PEACE_PERFORMANCE_V0_3: &str = "peace_performance_v0_3";
+
+/// The previous peace-performance release, linked as a renamed dependency,
+/// to compare pp changes between versions (`compare=all`).
+///
+/// Both versions have their own beatmap types, so v0.3 parses the stored `<md5>.osu` file
+/// itself, once per request. `rate` maps only exist as parsed v0.4 maps, so they are refused.
+///
+/// Other feature sets of a release (`no_sliders_no_leniency`) can not be compared this way:
+/// cargo links each release once, with the union of the features every dependent asks for.
+pub struct PeacePerformanceV0_3 {
+    pub osu_files_dir: String,
+}
+
+#[async_trait]
+impl PPAlgorithm for PeacePerformanceV0_3 {
+    #[inline(always)]
+    fn name(&self) -> &str {
+        PEACE_PERFORMANCE_V0_3
+    }
+
+    #[inline(always)]
+    fn description(&self) -> &str {
+        "peace-performance 0.3, the previous release"
+    }
+
+    async fn calculate(&self, beatmap: &PPbeatmap, data: &CalcData) -> Result<PpResult, String> {
+        if data.rate.is_some() {
+            return Err("rate is not supported by the previous release".to_string());
+        };
+        let md5 = data
+            .md5
+            .as_ref()
+            .ok_or_else(|| "md5 of the beatmap is unknown".to_string())?;
+        let path = format!("{}/{}.osu", self.osu_files_dir, md5);
+        let bytes = tokio::fs::read(&path)
+            .await
+            .map_err(|err| format!("cannot read '{}': {}", path, err))?;
+        let map = v0_3::Beatmap::parse(bytes.as_slice())
+            .await
+            .map_err(|err| format!("cannot parse '{}': {:?}", path, err))?;
+        let prev = calculate_pp(&map, calculator::calc_mode(beatmap, data), data).await;
+
+        // Only the type of the current release's result is used,
+        // every value the server reads from it is replaced with the previous release's
+        let mut result = calculator::calculate_pp(beatmap, data).await;
+        match (&mut result.attributes, &prev.attributes) {
+            (StarResult::Osu(a), v0_3::StarResult::Osu(p)) => {
+                a.stars = p.stars;
+                a.ar = p.ar;
+                a.od = p.od;
+                a.aim_strain = p.aim_strain;
+                a.speed_strain = p.speed_strain;
+                a.max_combo = p.max_combo;
+            }
+            (StarResult::Taiko(a), v0_3::StarResult::Taiko(p)) => a.stars = p.stars,
+            (StarResult::Fruits(a), v0_3::StarResult::Fruits(p)) => a.stars = p.stars,
+            (StarResult::Mania(a), v0_3::StarResult::Mania(p)) => a.stars = p.stars,
+            _ => return Err("the previous release calculated another mode".to_string()),
+        };
+        result.raw.aim = prev.raw.aim;
+        result.raw.spd = prev.raw.spd;
+        result.raw.acc = prev.raw.acc;
+        result.raw.str = prev.raw.str;
+        result.raw.total = prev.raw.total;
+        result.pp = prev.pp;
+        Ok(result)
+    }
+}
+
+async fn calculate_pp(map: &v0_3::Beatmap, mode: u8, data: &CalcData) -> v0_3::PpResult {
+    let c = match mode {
+        0 => v0_3::AnyPP::Osu(v0_3::OsuPP::new(map)),
+        1 => v0_3::AnyPP::Taiko(v0_3::TaikoPP::new(map)),
+        2 => v0_3::AnyPP::Fruits(v0_3::FruitsPP::new(map)),
+        3 => v0_3::AnyPP::Mania(v0_3::ManiaPP::new(map)),
+        _ => v0_3::AnyPP::new(map),
+    };
+    let c = set_calculator!(data.mods, c);
+    let c = set_calculator!(data.combo, c);
+    let c = set_calculator!(data.n50, c);
+    let c = set_calculator!(data.n100, c);
+    let c = set_calculator!(data.n300, c);
+    let c = set_calculator!(data.katu, n_katu, c);
+    let c = set_calculator!(data.miss, misses, c);
+    let c = set_calculator!(data.passed_obj, passed_objects, c);
+    let mut c = set_calculator!(data.score, c);
+    if let Some(acc) = data.acc {
+        c.set_accuracy(acc)
+    };
+    c.calculate().await
+}
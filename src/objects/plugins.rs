@@ -0,0 +1,281 @@
+use {
+    async_trait::async_trait,
+    notify::DebouncedEvent,
+    ntex::web::types::Data,
+    peace_performance::{Beatmap as PPbeatmap, PpResult},
+    rhai::{Dynamic, Engine, Scope, AST},
+    serde_json::{json, Value},
+    std::{path::Path, sync::Arc},
+};
+
+use crate::objects::{
+    algorithm::{self, PPAlgorithm},
+    calculator::{self, CalcData},
+    watcher,
+};
+use crate::settings::model::Algorithms;
+use crate::Glob;
+
+/// Function every plugin script defines: `fn pp(attributes, score)`, returns a number
+const PLUGIN_FN: &str = "pp";
+
+/// A pp formula written by the server admin as a rhai script.
+///
+/// The built-in algorithm calculates first, the script gets its difficulty attributes
+/// and the score, and returns the final pp. Scripts run sandboxed: rhai has no file
+/// or network access, and the engine limits operations, call depth and data sizes.
+pub struct ScriptPlugin {
+    pub name: String,
+    pub description: String,
+    engine: Arc<Engine>,
+    ast: AST,
+}
+
+impl ScriptPlugin {
+    fn call(&self, attributes: &Value, score: &Value) -> Result<f32, String> {
+        let attributes = rhai::serde::to_dynamic(attributes).map_err(|err| err.to_string())?;
+        let score = rhai::serde::to_dynamic(score).map_err(|err| err.to_string())?;
+        let result: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), &self.ast, PLUGIN_FN, (attributes, score))
+            .map_err(|err| err.to_string())?;
+        // `100` is an integer in rhai, `100.0` a float
+        let pp = match result.as_float() {
+            Ok(pp) => pp,
+            Err(_) => result
+                .as_int()
+                .map_err(|type_name| format!("pp must be a number, got {}", type_name))?
+                as f64,
+        };
+        if !pp.is_finite() || pp < 0.0 {
+            return Err(format!("pp must be a finite number >= 0, got {}", pp));
+        };
+        Ok(pp as f32)
+    }
+}
+
+#[async_trait]
+impl PPAlgorithm for ScriptPlugin {
+    #[inline(always)]
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline(always)]
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// A broken script fails the calculation, it should neither zero anyone's score
+    /// nor pass the built-in pp off as its own
+    async fn calculate(&self, beatmap: &PPbeatmap, data: &CalcData) -> Result<PpResult, String> {
+        let mut result = calculator::calculate_pp(beatmap, data).await;
+        let (attributes, score) = plugin_input(beatmap, data, &result);
+        let pp = self.call(&attributes, &score).map_err(|err| {
+            error!("[plugins] Plugin {} failed, err: {}", self.name, err);
+            err
+        })?;
+        result.pp = pp;
+        result.raw.total = pp;
+        Ok(result)
+    }
+}
+
+/// What the script gets: difficulty attributes (with the built-in pp) and the score
+pub fn plugin_input(beatmap: &PPbeatmap, data: &CalcData, result: &PpResult) -> (Value, Value) {
+    let max_combo = result.attributes.max_combo().unwrap_or(0);
+    let attributes = json!({
+        "stars": result.attributes.stars(),
+        "max_combo": max_combo,
+        "objects": beatmap.hit_objects.len(),
+        "ar": beatmap.ar,
+        "od": beatmap.od,
+        "cs": beatmap.cs,
+        "hp": beatmap.hp,
+        "pp": result.pp(),
+        "aim": result.raw.aim.unwrap_or(0.0),
+        "spd": result.raw.spd.unwrap_or(0.0),
+        "acc": result.raw.acc.unwrap_or(0.0),
+        "str": result.raw.str.unwrap_or(0.0),
+    });
+    let score = json!({
+        "mode": calculator::calc_mode(beatmap, data),
+        "mods": data.mods.unwrap_or(0),
+        "n300": data.n300.unwrap_or(0),
+        "n100": data.n100.unwrap_or(0),
+        "n50": data.n50.unwrap_or(0),
+        "katu": data.katu.unwrap_or(0),
+        "miss": data.miss.unwrap_or(0),
+        "combo": data.combo.unwrap_or(max_combo),
+        "passed_obj": data.passed_obj.unwrap_or(beatmap.hit_objects.len()),
+        "acc": calculator::play_accuracy(beatmap, data).or(data.acc).unwrap_or(100.0),
+    });
+    (attributes, score)
+}
+
+/// Sandboxed engine for plugin scripts
+pub fn plugin_engine(settings: &Algorithms) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(settings.plugin_max_operations)
+        .set_max_call_levels(16)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(10_000)
+        .set_max_array_size(10_000)
+        .set_max_map_size(1_000);
+    engine.disable_symbol("eval");
+    engine.on_print(|_| {});
+    engine.on_debug(|_, _, _| {});
+    engine
+}
+
+#[inline(always)]
+fn is_plugin_file(path: &Path) -> bool {
+    path.extension().map_or(false, |e| e == "rhai")
+}
+
+#[inline(always)]
+fn plugin_name(path: &Path) -> Option<String> {
+    path.file_stem()?.to_str().map(|s| s.to_string())
+}
+
+/// Compile a plugin script, it must define `fn pp(attributes, score)`
+pub fn load_plugin(engine: &Arc<Engine>, path: &Path) -> Result<ScriptPlugin, String> {
+    let name = plugin_name(path).ok_or_else(|| "invalid file name".to_string())?;
+    if algorithm::is_reserved_name(&name) {
+        return Err(format!("'{}' is a reserved algorithm name", name));
+    };
+    let script = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let ast = engine.compile(&script).map_err(|err| err.to_string())?;
+    if !ast
+        .iter_functions()
+        .any(|f| f.name == PLUGIN_FN && f.params.len() == 2)
+    {
+        return Err(format!(
+            "missing function: {}(attributes, score)",
+            PLUGIN_FN
+        ));
+    };
+    Ok(ScriptPlugin {
+        description: format!("plugin {:?}", path),
+        name,
+        engine: engine.clone(),
+        ast,
+    })
+}
+
+/// (Re)load one plugin file into the algorithm registry
+async fn reload_plugin(engine: &Arc<Engine>, path: &Path, glob: &Glob) {
+    match load_plugin(engine, path) {
+        Ok(plugin) => {
+            let name = plugin.name.clone();
+            glob.algorithms.register(Arc::new(plugin)).await;
+            info!("[plugins] Plugin {} loaded.", name);
+        }
+        Err(err) => warn!("[plugins] Failed to load plugin {:?}, err: {}", path, err),
+    }
+}
+
+/// A plugin file is gone, so is its algorithm.
+/// Files named like a built-in algorithm were never loaded, they don't remove anything.
+async fn unregister_plugin(path: &Path, glob: &Glob) {
+    if let Some(name) = plugin_name(path) {
+        if algorithm::is_reserved_name(&name) {
+            return;
+        };
+        if glob.algorithms.unregister(&name).await.is_some() {
+            info!("[plugins] Plugin {} removed.", name);
+        };
+    }
+}
+
+/// Load all plugins in `plugins_dir`
+pub async fn load_plugins(glob: &Glob) {
+    let settings = &glob.local_config.data.algorithms;
+    let engine = Arc::new(plugin_engine(settings));
+    let entries = match std::fs::read_dir(&settings.plugins_dir) {
+        Ok(entries) => entries,
+        Err(_) => {
+            info!(
+                "[plugins] Plugins dir '{}' not exists, no plugins loaded.",
+                settings.plugins_dir
+            );
+            return;
+        }
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.is_file() && is_plugin_file(&path) {
+            reload_plugin(&engine, &path, glob).await;
+        }
+    }
+}
+
+/// Watch `plugins_dir`, changed plugins are reloaded, removed ones unregistered
+pub fn start_plugins_watcher(glob: Data<Glob>, debounce: u64) {
+    let settings = &glob.local_config.data.algorithms;
+    let dir = settings.plugins_dir.clone();
+    let engine = Arc::new(plugin_engine(settings));
+    let mut receiver = watcher::watch_dir(dir, debounce, "plugins");
+
+    tokio::task::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
+                    if is_plugin_file(&path) {
+                        reload_plugin(&engine, &path, &glob).await;
+                    }
+                }
+                DebouncedEvent::Rename(from, to) => {
+                    if is_plugin_file(&from) {
+                        unregister_plugin(&from, &glob).await;
+                    };
+                    if is_plugin_file(&to) {
+                        reload_plugin(&engine, &to, &glob).await;
+                    }
+                }
+                DebouncedEvent::Remove(path) => {
+                    if is_plugin_file(&path) {
+                        unregister_plugin(&path, &glob).await;
+                    }
+                }
+                DebouncedEvent::Error(err, path) => {
+                    warn!("[plugins] Watcher error: {:?}, path: {:?}", err, path)
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::test_utils::temp_dir;
+
+    fn plugin(script: &str) -> ScriptPlugin {
+        let path = Path::new(&temp_dir("plugins")).join("test.rhai");
+        std::fs::write(&path, script).unwrap();
+        let engine = Arc::new(Engine::new());
+        load_plugin(&engine, &path).unwrap()
+    }
+
+    #[test]
+    fn plugin_returns_int_or_float() {
+        let (attributes, score) = (json!({ "pp": 100.5 }), json!({}));
+        let float = plugin("fn pp(attributes, score) { attributes.pp * 2.0 }");
+        assert_eq!(float.call(&attributes, &score), Ok(201.0));
+        let int = plugin("fn pp(attributes, score) { 300 }");
+        assert_eq!(int.call(&attributes, &score), Ok(300.0));
+    }
+
+    #[test]
+    fn plugin_errors_are_reported() {
+        let (attributes, score) = (json!({ "pp": 100.5 }), json!({}));
+        let string = plugin(r#"fn pp(attributes, score) { "300" }"#);
+        assert!(string.call(&attributes, &score).is_err());
+        let negative = plugin("fn pp(attributes, score) { -1 }");
+        assert!(negative.call(&attributes, &score).is_err());
+        let throws = plugin(r#"fn pp(attributes, score) { throw "broken" }"#);
+        assert!(throws.call(&attributes, &score).is_err());
+    }
+}
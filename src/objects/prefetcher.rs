@@ -0,0 +1,196 @@
+use {
+    chrono::Local,
+    ntex::web::types::Data,
+    serde::{Deserialize, Serialize},
+    serde_json::{json, Value},
+    std::{
+        collections::BTreeMap,
+        path::Path,
+        sync::atomic::{AtomicU64, Ordering},
+        time::{Duration, Instant},
+    },
+    tokio::sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Mutex, RwLock,
+    },
+};
+
+use crate::objects::calculator;
+use crate::Glob;
+
+/// Finished jobs kept for progress queries
+const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PrefetchTarget {
+    Bid(i32),
+    Md5(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrefetchJob {
+    pub id: u64,
+    pub status: &'static str,
+    pub total: usize,
+    pub done: usize,
+    pub fetched: usize,
+    pub cached: usize,
+    pub failed: usize,
+    pub failures: Vec<Value>,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+    #[serde(skip)]
+    pub targets: Vec<PrefetchTarget>,
+}
+
+/// Queue of beatmaps to download and parse in background, one at a time,
+/// through the same path (and rate limiter) as normal requests.
+pub struct Prefetcher {
+    sender: UnboundedSender<u64>,
+    receiver: Mutex<Option<UnboundedReceiver<u64>>>,
+    pub jobs: RwLock<BTreeMap<u64, PrefetchJob>>,
+    next_id: AtomicU64,
+}
+
+impl Prefetcher {
+    pub fn new() -> Self {
+        let (sender, receiver) = unbounded_channel();
+        Self {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            jobs: RwLock::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Queue a job, returns its id
+    pub async fn submit(&self, targets: Vec<PrefetchTarget>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let job = PrefetchJob {
+            id,
+            status: "queued",
+            total: targets.len(),
+            done: 0,
+            fetched: 0,
+            cached: 0,
+            failed: 0,
+            failures: Vec::new(),
+            created_at: Local::now().to_rfc3339(),
+            finished_at: None,
+            targets,
+        };
+        {
+            let mut jobs = self.jobs.write().await;
+            jobs.insert(id, job);
+            // Forget the oldest finished jobs
+            let finished: Vec<u64> = jobs
+                .values()
+                .filter(|j| j.status == "done")
+                .map(|j| j.id)
+                .collect();
+            if finished.len() > MAX_FINISHED_JOBS {
+                for id in finished.iter().take(finished.len() - MAX_FINISHED_JOBS) {
+                    jobs.remove(id);
+                }
+            };
+        }
+        let _ = self.sender.send(id);
+        id
+    }
+
+    #[inline(always)]
+    pub async fn get(&self, id: u64) -> Option<PrefetchJob> {
+        self.jobs.read().await.get(&id).cloned()
+    }
+
+    pub async fn list(&self) -> Value {
+        json!(self
+            .jobs
+            .read()
+            .await
+            .values()
+            .collect::<Vec<&PrefetchJob>>())
+    }
+
+    /// Start the background worker, wait `interval` (milliseconds) between two fetches
+    pub async fn start_worker(glob: Data<Glob>, interval: u64) {
+        let mut receiver = match glob.prefetcher.receiver.lock().await.take() {
+            Some(r) => r,
+            None => return,
+        };
+        let duration = Duration::from_millis(interval);
+        tokio::task::spawn(async move {
+            while let Some(id) = receiver.recv().await {
+                let targets = match glob.prefetcher.jobs.write().await.get_mut(&id) {
+                    Some(job) => {
+                        job.status = "running";
+                        job.targets.clone()
+                    }
+                    None => continue,
+                };
+                let start = Instant::now();
+                debug!(
+                    "[prefetcher] Job {} started, {} beatmaps.",
+                    id,
+                    targets.len()
+                );
+
+                for target in targets {
+                    let (md5, bid) = match &target {
+                        PrefetchTarget::Bid(bid) => (None, Some(*bid)),
+                        PrefetchTarget::Md5(md5) => (Some(md5.clone()), None),
+                    };
+                    // Already stored locally, nothing to download
+                    let stored = match (&md5, bid) {
+                        (Some(md5), _) => Some(md5.clone()),
+                        (None, Some(bid)) => glob.beatmap_index.get_by_bid(bid, false).await,
+                        _ => None,
+                    };
+                    let cached = stored.map_or(false, |m| {
+                        Path::new(&format!(
+                            "{}/{}.osu",
+                            glob.local_config.data.osu_files_dir, m
+                        ))
+                        .exists()
+                    });
+                    let result = if cached {
+                        Ok(true)
+                    } else {
+                        calculator::get_beatmap(md5, bid, None, None, &glob)
+                            .await
+                            .map(|_| false)
+                    };
+
+                    if let Some(job) = glob.prefetcher.jobs.write().await.get_mut(&id) {
+                        job.done += 1;
+                        match result {
+                            Ok(true) => job.cached += 1,
+                            Ok(false) => job.fetched += 1,
+                            Err(err) => {
+                                job.failed += 1;
+                                job.failures.push(json!({
+                                    "target": target,
+                                    "kind": err.error.kind(),
+                                    "message": err.error.error_message(),
+                                }));
+                            }
+                        }
+                    };
+                    if !cached {
+                        tokio::time::sleep(duration).await;
+                    };
+                }
+
+                if let Some(job) = glob.prefetcher.jobs.write().await.get_mut(&id) {
+                    job.status = "done";
+                    job.finished_at = Some(Local::now().to_rfc3339());
+                    info!(
+                        "[prefetcher] Job {} done, fetched: {}, cached: {}, failed: {} / total: {}; time spent: {:?}",
+                        id, job.fetched, job.cached, job.failed, job.total, start.elapsed()
+                    );
+                };
+            }
+        });
+    }
+}
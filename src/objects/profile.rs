@@ -0,0 +1,139 @@
+use serde::{Deserialize, Serialize};
+
+/// Each score is worth 95% of the one before it
+pub const SCORE_WEIGHT: f64 = 0.95;
+
+/// Only the best scores count for weighted pp and accuracy
+pub const MAX_WEIGHTED_SCORES: usize = 100;
+
+/// A score is `300.5` (pp only) or `{"pp": 300.5, "acc": 98.9}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "ProfileScoreInput")]
+pub struct ProfileScore {
+    pub pp: f64,
+    /// 0 ~ 100
+    pub acc: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ProfileScoreInput {
+    Pp(f64),
+    Score { pp: f64, acc: Option<f64> },
+}
+
+impl From<ProfileScoreInput> for ProfileScore {
+    #[inline(always)]
+    fn from(input: ProfileScoreInput) -> Self {
+        match input {
+            ProfileScoreInput::Pp(pp) => Self { pp, acc: None },
+            ProfileScoreInput::Score { pp, acc } => Self { pp, acc },
+        }
+    }
+}
+
+impl ProfileScore {
+    /// pp is not negative, acc (if given) is in 0 ~ 100
+    #[inline(always)]
+    pub fn is_valid(&self) -> bool {
+        self.pp.is_finite()
+            && self.pp >= 0.0
+            && self
+                .acc
+                .map_or(true, |acc| acc.is_finite() && (0.0..=100.0).contains(&acc))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileStats {
+    /// Weighted pp + bonus pp
+    pub pp: f64,
+    pub weighted_pp: f64,
+    pub bonus_pp: f64,
+    pub acc: f64,
+    pub score_count: usize,
+}
+
+/// Bonus pp from the count of ranked scores, at most 416.67
+#[inline(always)]
+pub fn bonus_pp(score_count: usize) -> f64 {
+    416.6667 * (1.0 - 0.9994f64.powi(score_count as i32))
+}
+
+/// Weighted pp (0.95^n), bonus pp and weighted accuracy of the best scores.
+/// Scores without acc keep their pp weight, but are left out of the accuracy.
+/// `score_count` is the count of all ranked scores (for bonus pp), at least the scores given.
+pub fn profile_stats(scores: &[ProfileScore], score_count: Option<usize>) -> ProfileStats {
+    let mut sorted: Vec<&ProfileScore> = scores.iter().collect();
+    sorted.sort_by(|a, b| b.pp.partial_cmp(&a.pp).unwrap_or(std::cmp::Ordering::Equal));
+
+    let (mut weighted_pp, mut weighted_acc, mut acc_weight_sum) = (0.0, 0.0, 0.0);
+    for (i, score) in sorted.iter().take(MAX_WEIGHTED_SCORES).enumerate() {
+        let weight = SCORE_WEIGHT.powi(i as i32);
+        weighted_pp += score.pp * weight;
+        if let Some(acc) = score.acc {
+            weighted_acc += acc * weight;
+            acc_weight_sum += weight;
+        };
+    }
+    let score_count = score_count.unwrap_or(0).max(scores.len());
+    let bonus_pp = bonus_pp(score_count);
+    ProfileStats {
+        pp: weighted_pp + bonus_pp,
+        weighted_pp,
+        bonus_pp,
+        acc: if acc_weight_sum > 0.0 {
+            weighted_acc / acc_weight_sum
+        } else {
+            0.0
+        },
+        score_count,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(pp: f64, acc: Option<f64>) -> ProfileScore {
+        ProfileScore { pp, acc }
+    }
+
+    #[test]
+    fn scores_without_acc_are_left_out_of_acc() {
+        let stats = profile_stats(&[score(200.0, None), score(100.0, Some(98.0))], None);
+        assert_eq!(stats.acc, 98.0);
+        assert_eq!(stats.weighted_pp, 200.0 + 100.0 * SCORE_WEIGHT);
+        assert_eq!(stats.score_count, 2);
+        assert_eq!(profile_stats(&[score(100.0, None)], None).acc, 0.0);
+    }
+
+    #[test]
+    fn scores_are_numbers_or_objects() {
+        let scores: Vec<ProfileScore> =
+            serde_json::from_str(r#"[300.5, 280, {"pp": 250.0, "acc": 98.9}, {"pp": 200}]"#)
+                .unwrap();
+        let scores: Vec<(f64, Option<f64>)> = scores.iter().map(|s| (s.pp, s.acc)).collect();
+        assert_eq!(
+            scores,
+            vec![
+                (300.5, None),
+                (280.0, None),
+                (250.0, Some(98.9)),
+                (200.0, None)
+            ]
+        );
+        assert!(serde_json::from_str::<ProfileScore>(r#""300""#).is_err());
+        assert!(serde_json::from_str::<ProfileScore>(r#"{"acc": 98.9}"#).is_err());
+    }
+
+    #[test]
+    fn validates_pp_and_acc() {
+        assert!(score(0.0, None).is_valid());
+        assert!(score(300.0, Some(100.0)).is_valid());
+        assert!(!score(-1.0, None).is_valid());
+        assert!(!score(f64::NAN, None).is_valid());
+        assert!(!score(300.0, Some(100.1)).is_valid());
+        assert!(!score(300.0, Some(f64::NAN)).is_valid());
+    }
+}
@@ -0,0 +1,276 @@
+use {
+    prometheus::{opts, IntCounterVec},
+    serde_json::{json, Value},
+    std::time::{Duration, Instant},
+    tokio::sync::Mutex,
+};
+
+use crate::settings::model::RateLimit;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitError {
+    /// No bucket can give a token within `max_wait`
+    Throttled,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket per key, requests are rotated across all keys.
+/// A request waits at most `max_wait` for a token (waiting requests are served first come,
+/// first served), if it would need to wait longer, it fails immediately with `RateLimitError::Throttled`.
+pub struct KeyedRateLimiter<T> {
+    pub name: String,
+    pub keys: Vec<T>,
+    pub settings: RateLimit,
+    buckets: Mutex<(Vec<Bucket>, usize)>,
+    pub counter: IntCounterVec,
+}
+
+/// Metric shared by all limiters, labeled with limiter name, key index and result
+pub fn osu_api_requests_counter(namespace: &str) -> IntCounterVec {
+    let counter = IntCounterVec::new(
+        opts!(
+            "osu_api_requests",
+            "osu!api requests allowed or throttled by the rate limiter"
+        )
+        .namespace(namespace.to_string()),
+        &["limiter", "key", "result"],
+    )
+    .unwrap();
+    if let Err(err) = prometheus::register(Box::new(counter.clone())) {
+        warn!("[rate_limiter] Failed to register metrics, err: {:?}", err);
+    };
+    counter
+}
+
+impl<T> KeyedRateLimiter<T> {
+    pub fn new(name: &str, keys: Vec<T>, settings: &RateLimit, counter: IntCounterVec) -> Self {
+        let now = Instant::now();
+        let buckets = keys
+            .iter()
+            .map(|_| Bucket {
+                tokens: settings.burst as f64,
+                last_refill: now,
+            })
+            .collect();
+        Self {
+            name: name.to_string(),
+            keys,
+            settings: settings.clone(),
+            buckets: Mutex::new((buckets, 0)),
+            counter,
+        }
+    }
+
+    #[inline(always)]
+    fn refill_rate(&self) -> f64 {
+        self.settings.requests_per_minute as f64 / 60.0
+    }
+
+    /// Take a token from the next key that has one, and return that key.
+    ///
+    /// If no key has a token, the request reserves the next one (tokens go below zero)
+    /// and sleeps until it is due: requests get their tokens in the order they came in.
+    pub async fn acquire(&self) -> Result<(usize, &T), RateLimitError> {
+        if !self.settings.enabled {
+            let mut guard = self.buckets.lock().await;
+            let idx = guard.1 % self.keys.len().max(1);
+            guard.1 = idx + 1;
+            return self
+                .keys
+                .get(idx)
+                .map(|k| (idx, k))
+                .ok_or(RateLimitError::Throttled);
+        };
+
+        let max_wait = Duration::from_millis(self.settings.max_wait);
+        let rate = self.refill_rate();
+        let reserved = {
+            let mut guard = self.buckets.lock().await;
+            let (buckets, cursor) = &mut *guard;
+            let total = buckets.len();
+            let now = Instant::now();
+            for b in buckets.iter_mut() {
+                let elapsed = now.duration_since(b.last_refill).as_secs_f64();
+                b.tokens = (b.tokens + elapsed * rate).min(self.settings.burst as f64);
+                b.last_refill = now;
+            }
+            // Round robin, start from the key after the last used one;
+            // if none has a token, the one that has its next token first
+            let next = (0..total)
+                .map(|i| (*cursor + i) % total)
+                .find(|idx| buckets[*idx].tokens >= 1.0)
+                .or_else(|| {
+                    // max_by returns the last max, so reversed it is the first one from cursor
+                    (0..total)
+                        .rev()
+                        .map(|i| (*cursor + i) % total)
+                        .max_by(|a, b| {
+                            buckets[*a]
+                                .tokens
+                                .partial_cmp(&buckets[*b].tokens)
+                                .unwrap_or(std::cmp::Ordering::Equal)
+                        })
+                });
+            match next {
+                Some(idx) => {
+                    let missing = 1.0 - buckets[idx].tokens;
+                    let wait = if missing <= 0.0 {
+                        Some(Duration::from_secs(0))
+                    } else if rate > 0.0 {
+                        Some(Duration::from_secs_f64(missing / rate))
+                    } else {
+                        None
+                    };
+                    match wait {
+                        Some(wait) if wait <= max_wait => {
+                            buckets[idx].tokens -= 1.0;
+                            *cursor = idx + 1;
+                            Some((idx, wait))
+                        }
+                        _ => None,
+                    }
+                }
+                None => None,
+            }
+        };
+
+        match reserved {
+            Some((idx, wait)) => {
+                if wait > Duration::from_secs(0) {
+                    tokio::time::sleep(wait).await;
+                };
+                self.count(idx, "allowed");
+                Ok((idx, &self.keys[idx]))
+            }
+            None => {
+                self.count(usize::MAX, "throttled");
+                warn!(
+                    "[rate_limiter] {} budget exceeded, request throttled.",
+                    self.name
+                );
+                Err(RateLimitError::Throttled)
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn count(&self, idx: usize, result: &str) {
+        let key = if idx == usize::MAX {
+            "any".to_string()
+        } else {
+            format!("key_{}", idx)
+        };
+        self.counter
+            .with_label_values(&[&self.name, &key, result])
+            .inc();
+    }
+
+    pub async fn stats(&self) -> Value {
+        let guard = self.buckets.lock().await;
+        let now = Instant::now();
+        let rate = self.refill_rate();
+        json!({
+            "name": self.name,
+            "enabled": self.settings.enabled,
+            "requests_per_minute": self.settings.requests_per_minute,
+            "burst": self.settings.burst,
+            "max_wait": self.settings.max_wait,
+            "throttled": self.counter.with_label_values(&[&self.name, "any", "throttled"]).get(),
+            "keys": guard.0.iter().enumerate().map(|(idx, b)| {
+                let elapsed = now.duration_since(b.last_refill).as_secs_f64();
+                json!({
+                    "key": format!("key_{}", idx),
+                    // Below zero: tokens reserved by waiting requests
+                    "tokens": (b.tokens + elapsed * rate).min(self.settings.burst as f64),
+                    "allowed": self.counter.with_label_values(&[&self.name, &format!("key_{}", idx), "allowed"]).get(),
+                })
+            }).collect::<Vec<Value>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::sync::Arc};
+
+    fn limiter(
+        keys: usize,
+        requests_per_minute: u32,
+        burst: u32,
+        max_wait: u64,
+    ) -> KeyedRateLimiter<usize> {
+        let counter =
+            IntCounterVec::new(opts!("test", "test"), &["limiter", "key", "result"]).unwrap();
+        KeyedRateLimiter::new(
+            "test",
+            (0..keys).collect(),
+            &RateLimit {
+                enabled: true,
+                requests_per_minute,
+                burst,
+                max_wait,
+            },
+            counter,
+        )
+    }
+
+    #[tokio::test]
+    async fn burst_then_throttled() {
+        let limiter = limiter(1, 60, 3, 0);
+        for _ in 0..3 {
+            assert!(limiter.acquire().await.is_ok());
+        }
+        assert_eq!(limiter.acquire().await, Err(RateLimitError::Throttled));
+    }
+
+    #[tokio::test]
+    async fn keys_are_rotated() {
+        let limiter = limiter(2, 60, 1, 0);
+        assert_eq!(limiter.acquire().await.map(|(idx, _)| idx), Ok(0));
+        assert_eq!(limiter.acquire().await.map(|(idx, _)| idx), Ok(1));
+        assert!(limiter.acquire().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn waits_for_refill() {
+        // A token every 100ms
+        let limiter = limiter(1, 600, 1, 1000);
+        assert!(limiter.acquire().await.is_ok());
+        let start = Instant::now();
+        assert!(limiter.acquire().await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(90));
+
+        // The next token is 100ms away, more than max_wait
+        let limiter = self::limiter(1, 600, 1, 50);
+        assert!(limiter.acquire().await.is_ok());
+        let start = Instant::now();
+        assert!(limiter.acquire().await.is_err());
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn waiters_are_served_in_order() {
+        // A token every 50ms
+        let limiter = Arc::new(limiter(1, 1200, 1, 2000));
+        assert!(limiter.acquire().await.is_ok());
+        let served = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for i in 0..5 {
+            let (limiter, served) = (limiter.clone(), served.clone());
+            tasks.push(tokio::spawn(async move {
+                limiter.acquire().await.unwrap();
+                served.lock().unwrap().push(i);
+            }));
+            // Let it queue up before the next one comes
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(*served.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+}
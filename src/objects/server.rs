@@ -19,6 +19,7 @@ use {
 use tokio::sync::Mutex;
 
 use crate::{
+    objects::{beatmap_index::BeatmapIndex, plugins, prefetcher::Prefetcher, watcher},
     settings::model::LocalConfigData,
     Glob, {routes, utils},
 };
@@ -104,6 +105,21 @@ impl PPserver {
 
     pub async fn start(&mut self) -> std::io::Result<()> {
         let config = &self.glob.local_config.data;
+        utils::checking_osu_dir(
+            &config.osu_files_dir,
+            config.recalculate_osu_file_md5,
+            &self.glob.beatmap_index,
+        )
+        .await;
+        utils::index_osu_files(&self.glob).await;
+        BeatmapIndex::start_writer(
+            self.glob.beatmap_index.clone(),
+            config.beatmap_index_save_interval,
+        );
+        if config.watch_osu_files_dir {
+            watcher::start_osu_dir_watcher(self.glob.clone(), config.watch_debounce);
+        };
+
         // Should preload or not
         if config.preload_osu_files {
             utils::preload_osu_files(
@@ -116,6 +132,14 @@ impl PPserver {
 
         self.start_auto_cache_clean(config.auto_clean_interval, config.beatmap_cache_timeout)
             .await;
+        Prefetcher::start_worker(self.glob.clone(), config.prefetch.interval).await;
+        plugins::load_plugins(&self.glob).await;
+        if config.algorithms.watch_plugins_dir {
+            plugins::start_plugins_watcher(
+                self.glob.clone(),
+                config.algorithms.watch_plugins_debounce,
+            );
+        };
         #[cfg(feature = "with_peace")]
         self.start_auto_pp_recalculate(
             config.auto_pp_recalculate.interval,
@@ -150,6 +174,13 @@ impl PPserver {
                 // release read lock
                 drop(pp_beatmap_cache);
 
+                // Clean expired negative cache
+                caches
+                    .negative_cache
+                    .write()
+                    .await
+                    .retain(|_, v| v.remaining() > 0);
+
                 // Clean timeout cache
                 if ready_to_clean.len() > 0 {
                     debug!("[auto_cache_clean] Timeout cache founded, will clean them...");
@@ -287,9 +318,9 @@ impl PPserver {
                             )
                             .await
                             {
-                                Some(b) => b,
-                                None => {
-                                    warn!("[auto_pp_recalculate] Failed to get beatmap, key: {}, data: {:?}; try_count: {}", key, data, try_count);
+                                Ok(b) => b,
+                                Err(err) => {
+                                    warn!("[auto_pp_recalculate] Failed to get beatmap, key: {}, data: {:?}; try_count: {}, err: {:?}", key, data, try_count, err.error);
                                     failed += 1;
                                     let _ = database
                                         .redis
@@ -298,8 +329,42 @@ impl PPserver {
                                     continue;
                                 }
                             };
+                            // The same as /api/calc
+                            let calculator::PreparedScore {
+                                beatmap,
+                                data,
+                                algorithm,
+                                ..
+                            } = match calculator::prepare_score(beatmap, data, &glob.algorithms)
+                                .await
+                            {
+                                Ok(p) => p,
+                                Err(err) => {
+                                    warn!("[auto_pp_recalculate] Invalid calc data, key: {}, remove it; err: {}", key, err.error_message());
+                                    failed += 1;
+                                    let _ = database.redis.del(key).await;
+                                    continue;
+                                }
+                            };
                             // calculate.
-                            let r = calculator::calculate_pp(&beatmap, &data).await;
+                            let r = match algorithm.calculate(&beatmap, &data).await {
+                                Ok(r) => r,
+                                Err(err) => {
+                                    warn!("[auto_pp_recalculate] Algorithm {} failed, key: {}; try_count: {}, err: {}", algorithm.name(), key, try_count, err);
+                                    failed += 1;
+                                    let _ = database
+                                        .redis
+                                        .set(&key, format!("{}:{}", try_count + 1, values[1]))
+                                        .await;
+                                    continue;
+                                }
+                            };
+                            if let Err(err) = calculator::validate_combo(&data, &r) {
+                                warn!("[auto_pp_recalculate] Invalid hit counts, key: {}, remove it; err: {}", key, err.error_message());
+                                failed += 1;
+                                let _ = database.redis.del(key).await;
+                                continue;
+                            };
 
                             // Save it
                             match database.pg.query_first(
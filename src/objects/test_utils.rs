@@ -0,0 +1,176 @@
+use {
+    std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    },
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    },
+};
+
+/// A request received by the stand-in server
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub time: Instant,
+}
+
+impl Request {
+    #[inline(always)]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A local HTTP server standing in for osu! or a mirror.
+/// The handler gets each request and its index, and answers with (status, body).
+pub struct StandIn {
+    pub url: String,
+    pub requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl StandIn {
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&Request, usize) -> (u16, String) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler = Arc::new(handler);
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let request = match read_request(&mut stream).await {
+                    Some(r) => r,
+                    None => continue,
+                };
+                let index = {
+                    let mut requests = recorded.lock().unwrap();
+                    requests.push(request.clone());
+                    requests.len() - 1
+                };
+                let (status, body) = handler(&request, index);
+                let response = format!(
+                    "HTTP/1.1 {} STAND-IN\r\nContent-Length: {}\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        Self { url, requests }
+    }
+
+    #[inline(always)]
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    #[inline(always)]
+    pub fn count(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+}
+
+async fn read_request(stream: &mut tokio::net::TcpStream) -> Option<Request> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        };
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        };
+    };
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| {
+            let i = l.find(':')?;
+            Some((l[..i].trim().to_string(), l[i + 1..].trim().to_string()))
+        })
+        .collect();
+    let length = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < head_end + length {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            break;
+        };
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Some(Request {
+        method,
+        path,
+        headers,
+        body: String::from_utf8_lossy(&buf[head_end..]).to_string(),
+        time: Instant::now(),
+    })
+}
+
+/// A small but valid osu!std .osu file, `version` changes its content (and md5)
+pub fn osu_file(bid: i32, version: &str) -> String {
+    format!(
+        "osu file format v14
+
+[General]
+Mode: 0
+
+[Metadata]
+Title:Stand In
+Artist:Peace
+Creator:pp-server
+Version:{}
+BeatmapID:{}
+BeatmapSetID:1
+
+[Difficulty]
+HPDrainRate:5
+CircleSize:4
+OverallDifficulty:8
+ApproachRate:9
+SliderMultiplier:1.4
+SliderTickRate:1
+
+[TimingPoints]
+0,500,4,2,0,100,1,0
+
+[HitObjects]
+256,192,1000,1,0,0:0:0:0:
+128,96,1500,1,0,0:0:0:0:
+384,288,2000,1,0,0:0:0:0:
+",
+        version, bid
+    )
+}
+
+/// A fresh empty dir under the system temp dir
+pub fn temp_dir(name: &str) -> String {
+    let dir = std::env::temp_dir().join(format!(
+        "pp-server-test-{}-{}-{}",
+        name,
+        std::process::id(),
+        chrono::Local::now().timestamp_nanos()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.to_string_lossy().to_string()
+}
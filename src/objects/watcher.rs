@@ -0,0 +1,168 @@
+use {
+    bytes::Bytes,
+    notify::{watcher, DebouncedEvent, RecursiveMode, Watcher},
+    ntex::web::types::Data,
+    std::{
+        path::{Path, PathBuf},
+        time::Duration,
+    },
+    tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver},
+};
+
+use crate::objects::osu_files::{self, OsuFileMetadata};
+use crate::Glob;
+
+/// `<md5>.osu` file name stem, if it looks like one
+#[inline(always)]
+pub fn md5_file_stem(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?;
+    if stem.len() == 32 && stem.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(stem.to_lowercase())
+    } else {
+        None
+    }
+}
+
+#[inline(always)]
+fn is_osu_file(path: &Path) -> bool {
+    path.extension().map_or(false, |e| e == "osu")
+}
+
+/// Watch a dir (not recursive) with notify, which is blocking and runs in its own thread;
+/// its debounced events are sent to the returned receiver
+pub fn watch_dir(
+    dir: String,
+    debounce: u64,
+    log_prefix: &'static str,
+) -> UnboundedReceiver<DebouncedEvent> {
+    let (sender, receiver) = unbounded_channel();
+    std::thread::spawn(move || {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut w = match watcher(tx, Duration::from_secs(debounce)) {
+            Ok(w) => w,
+            Err(err) => {
+                error!("[{}] Failed to create watcher, err: {:?}", log_prefix, err);
+                return;
+            }
+        };
+        if let Err(err) = w.watch(&dir, RecursiveMode::NonRecursive) {
+            error!(
+                "[{}] Failed to watch dir '{}', err: {:?}",
+                log_prefix, dir, err
+            );
+            return;
+        };
+        info!("[{}] Watching dir '{}'", log_prefix, dir);
+        while let Ok(event) = rx.recv() {
+            if sender.send(event).is_err() {
+                break;
+            };
+        }
+    });
+    receiver
+}
+
+/// Watch `osu_files_dir`, new or changed .osu files will be renamed to `<md5>.osu`,
+/// indexed, and the cache entries they replace will be invalidated.
+pub fn start_osu_dir_watcher(glob: Data<Glob>, debounce: u64) {
+    let dir = glob.local_config.data.osu_files_dir.clone();
+    let mut receiver = watch_dir(dir, debounce, "osu_dir_watcher");
+
+    tokio::task::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
+                    if is_osu_file(&path) {
+                        handle_osu_file(path, &glob).await;
+                    }
+                }
+                DebouncedEvent::Rename(from, to) => {
+                    if is_osu_file(&from) && md5_file_stem(&from) != md5_file_stem(&to) {
+                        handle_removed_file(&from, &glob).await;
+                    };
+                    if is_osu_file(&to) {
+                        handle_osu_file(to, &glob).await;
+                    }
+                }
+                DebouncedEvent::Remove(path) => handle_removed_file(&path, &glob).await,
+                DebouncedEvent::Error(err, path) => {
+                    warn!("[osu_dir_watcher] Error: {:?}, path: {:?}", err, path)
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+/// A `<md5>.osu` file is gone, so is its md5
+pub async fn handle_removed_file(path: &Path, glob: &Glob) {
+    if let Some(md5) = md5_file_stem(path) {
+        debug!("[osu_dir_watcher] .osu file removed: {}", md5);
+        glob.caches.remove_pp_beatmap(&md5).await;
+        // Saved by the index writer
+        glob.beatmap_index.remove(&md5).await;
+    }
+}
+
+/// Rename (if needed) and index a new or changed .osu file
+pub async fn handle_osu_file(path: PathBuf, glob: &Glob) {
+    let bytes = match tokio::fs::read(&path).await {
+        Ok(b) => Bytes::from(b),
+        // Moved away again, nothing to do
+        Err(_) => return,
+    };
+    // Our own downloads and imports (`<md5>.osu.tmp` -> `<md5>.osu`) are already indexed
+    if let Some(md5) = md5_file_stem(&path).filter(|m| m == &osu_files::bytes_md5(&bytes)) {
+        let metadata = OsuFileMetadata::from_bytes(&bytes);
+        if glob.beatmap_index.contains_metadata(&md5, &metadata).await {
+            debug!("[osu_dir_watcher] .osu file already indexed: {}", md5);
+            return;
+        };
+    };
+    let md5 = match osu_files::verify_osu_file(&bytes, None).await {
+        Ok((_, md5)) => md5,
+        Err(err) => {
+            warn!(
+                "[osu_dir_watcher] Ignored invalid .osu file {:?}, reason: {}",
+                path,
+                err.reason()
+            );
+            return;
+        }
+    };
+
+    // Event paths may be absolute, only compare the file names
+    let target = path.with_file_name(format!("{}.osu", md5));
+    if path.file_name() != target.file_name() {
+        // File was named as another md5 and changed in place, that md5 is gone now
+        if let Some(old_md5) = md5_file_stem(&path) {
+            glob.caches.remove_pp_beatmap(&old_md5).await;
+            glob.beatmap_index.remove(&old_md5).await;
+        };
+        let result = if target.exists() {
+            // Same content already stored
+            tokio::fs::remove_file(&path).await
+        } else {
+            tokio::fs::rename(&path, &target).await
+        };
+        if let Err(err) = result {
+            warn!(
+                "[osu_dir_watcher] Failed to move {:?} to {:?}, err: {:?}",
+                path, target, err
+            );
+            return;
+        };
+    };
+
+    let metadata = OsuFileMetadata::from_bytes(&bytes);
+    glob.caches
+        .invalidate_beatmap(&md5, metadata.bid, metadata.sid)
+        .await;
+    glob.beatmap_index
+        .insert_metadata(&md5, &metadata, None, None)
+        .await;
+    debug!(
+        "[osu_dir_watcher] .osu file indexed: {}, bid: {:?}, sid: {:?}",
+        md5, metadata.bid, metadata.sid
+    );
+}
@@ -0,0 +1,238 @@
+use {
+    bytes::Bytes,
+    ntex::web::{
+        get, post,
+        types::{Data, Path, Query},
+        HttpRequest, HttpResponse,
+    },
+    serde::Deserialize,
+    serde_json::{json, Value},
+};
+
+use crate::{
+    objects::{
+        importer::{self, ImportResult},
+        osu_files::OsuFileMetadata,
+        prefetcher::PrefetchTarget,
+    },
+    Glob,
+};
+
+/// Admin routes need header "Authorization: Bearer <admin_key>"
+#[inline(always)]
+pub fn authorized(req: &HttpRequest, glob: &Glob) -> bool {
+    let key = &glob.local_config.data.admin_key;
+    if key.is_empty() {
+        return false;
+    };
+    req.headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v == format!("Bearer {}", key))
+}
+
+#[inline(always)]
+pub fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .content_type("application/json")
+        .body(json!({"status": 0, "message": "unauthorized"}))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OfflineSwitch {
+    pub enabled: u8,
+}
+
+/// GET "/admin/offline"
+#[get("/offline")]
+pub async fn offline_status(req: HttpRequest, glob: Data<Glob>) -> HttpResponse {
+    if !authorized(&req, &glob) {
+        return unauthorized();
+    };
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({"status": 1, "offline": glob.is_offline()}))
+}
+
+/// POST "/admin/offline?enabled=0|1"
+#[post("/offline")]
+pub async fn offline_switch(
+    req: HttpRequest,
+    glob: Data<Glob>,
+    query: Query<OfflineSwitch>,
+) -> HttpResponse {
+    if !authorized(&req, &glob) {
+        return unauthorized();
+    };
+    let offline = query.enabled > 0;
+    glob.set_offline(offline);
+    warn!("[admin] Offline mode switched to: {}", offline);
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({"status": 1, "offline": offline}))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadQuery {
+    pub bid: Option<i32>,
+    pub sid: Option<i32>,
+}
+
+/// POST "/admin/upload?bid=&sid="
+///
+/// Body is a .osu file or a .osz archive, bid can only be given for exactly one difficulty.
+/// The bid and sid given are pinned, they are never re-checked upstream.
+#[post("/upload")]
+pub async fn upload(
+    req: HttpRequest,
+    glob: Data<Glob>,
+    query: Query<UploadQuery>,
+    body: Bytes,
+) -> HttpResponse {
+    if !authorized(&req, &glob) {
+        return unauthorized();
+    };
+    let files = if importer::is_osz(&body) {
+        match importer::read_osz(&body) {
+            Ok(files) => files,
+            Err(err) => {
+                return HttpResponse::BadRequest()
+                    .content_type("application/json")
+                    .body(json!({"status": 0, "message": format!("invalid .osz file: {}", err)}))
+            }
+        }
+    } else {
+        vec![("upload.osu".to_string(), body)]
+    };
+    if query.bid.is_some() && files.len() != 1 {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({
+                "status": 0,
+                "message": format!("bid given for {} difficulties, need exactly one", files.len())
+            }));
+    };
+    let (bid, pinned) = (query.bid, query.bid.is_some() || query.sid.is_some());
+
+    let dir = &glob.local_config.data.osu_files_dir;
+    let mut results = Vec::with_capacity(files.len());
+    for (name, bytes) in files {
+        let result =
+            importer::import_osu_bytes(&bytes, dir, &glob.beatmap_index, bid, query.sid).await;
+        let value = match &result {
+            ImportResult::Imported(md5) | ImportResult::Duplicate(md5) => {
+                if pinned {
+                    glob.beatmap_index.pin(md5).await;
+                };
+                let metadata = OsuFileMetadata::from_bytes(&bytes);
+                glob.caches
+                    .invalidate_beatmap(md5, bid.or(metadata.bid), query.sid.or(metadata.sid))
+                    .await;
+                json!({
+                    "name": name,
+                    "result": if let ImportResult::Imported(_) = result { "imported" } else { "duplicate" },
+                    "md5": md5,
+                })
+            }
+            ImportResult::Invalid(reason) => json!({
+                "name": name,
+                "result": "invalid",
+                "reason": reason,
+            }),
+        };
+        results.push(value);
+    }
+    glob.beatmap_index.save().await;
+    info!(
+        "[admin] Uploaded {} .osu files, bid: {:?}, sid: {:?}",
+        results.len(),
+        bid,
+        query.sid
+    );
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({"status": 1, "files": Value::Array(results)}))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PrefetchRequest {
+    pub bids: Option<Vec<i32>>,
+    pub md5s: Option<Vec<String>>,
+}
+
+/// POST "/admin/prefetch"
+///
+/// Body: `{"bids": [...], "md5s": [...]}`, returns the job id
+#[post("/prefetch")]
+pub async fn prefetch(req: HttpRequest, glob: Data<Glob>, body: Bytes) -> HttpResponse {
+    if !authorized(&req, &glob) {
+        return unauthorized();
+    };
+    let failed = |message: String| {
+        HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({"status": 0, "message": message}))
+    };
+    let data = match serde_json::from_slice::<PrefetchRequest>(&body) {
+        Ok(d) => d,
+        Err(err) => return failed(err.to_string()),
+    };
+
+    let mut targets: Vec<PrefetchTarget> = data
+        .bids
+        .unwrap_or_default()
+        .into_iter()
+        .map(PrefetchTarget::Bid)
+        .collect();
+    for md5 in data.md5s.unwrap_or_default() {
+        if md5.len() != 32 {
+            return failed(format!("invalid md5: {}", md5));
+        };
+        targets.push(PrefetchTarget::Md5(peace_utils::common::safe_string(
+            md5.to_lowercase(),
+        )));
+    }
+    let max_targets = glob.local_config.data.prefetch.max_targets;
+    if targets.is_empty() || targets.len() > max_targets {
+        return failed(format!(
+            "need 1 to {} beatmaps, got {}",
+            max_targets,
+            targets.len()
+        ));
+    };
+
+    let total = targets.len();
+    let id = glob.prefetcher.submit(targets).await;
+    info!("[admin] Prefetch job {} queued, {} beatmaps.", id, total);
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({"status": 1, "id": id, "total": total}))
+}
+
+/// GET "/admin/prefetch"
+#[get("/prefetch")]
+pub async fn prefetch_jobs(req: HttpRequest, glob: Data<Glob>) -> HttpResponse {
+    if !authorized(&req, &glob) {
+        return unauthorized();
+    };
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({"status": 1, "jobs": glob.prefetcher.list().await}))
+}
+
+/// GET "/admin/prefetch/{id}"
+#[get("/prefetch/{id}")]
+pub async fn prefetch_job(req: HttpRequest, glob: Data<Glob>, id: Path<u64>) -> HttpResponse {
+    if !authorized(&req, &glob) {
+        return unauthorized();
+    };
+    match glob.prefetcher.get(id.into_inner()).await {
+        Some(job) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({"status": 1, "job": job})),
+        None => HttpResponse::NotFound()
+            .content_type("application/json")
+            .body(json!({"status": 0, "message": "job not found"})),
+    }
+}
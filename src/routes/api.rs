@@ -1,18 +1,23 @@
 use {
     askama::Template,
+    bytes::Bytes,
     ntex::web::{
-        get,
+        get, post,
         types::{Data, Query},
         HttpRequest, HttpResponse,
     },
-    peace_performance::PpResult,
-    serde_json::json,
-    serde_json::Value::Null,
+    peace_performance::{Beatmap as PPbeatmap, PpResult},
+    serde::Deserialize,
+    serde_json::{json, Value, Value::Null},
     std::time::Instant,
 };
 
 use crate::{
-    objects::calculator::{self, CalcData},
+    objects::{
+        algorithm,
+        calculator::{self, CalcData, PreparedScore},
+        profile::{self, ProfileScore},
+    },
     Glob,
 };
 
@@ -24,102 +29,257 @@ pub async fn index(glob: Data<Glob>) -> HttpResponse {
         .body(glob.render_main_page.render().unwrap())
 }
 
-// calculate pp (used by peace)
-#[get("/calc")]
-pub async fn calculate_pp(req: HttpRequest, glob: Data<Glob>) -> HttpResponse {
-    let get_raw = |result: &PpResult| {
-        json!({
-            "aim": result.raw.aim.unwrap_or(0.0),
-            "spd": result.raw.spd.unwrap_or(0.0),
-            "acc": result.raw.acc.unwrap_or(0.0),
-            "str": result.raw.str.unwrap_or(0.0),
-            "total": result.raw.total,
-        })
-    };
-    let failed = |status, message| {
-        HttpResponse::Ok()
-            .content_type("application/json")
-            .body(json!(
-                {
-                    "status": status,
-                    "message": message,
-                    "pp": null
-                }
-            ))
-    };
-    let start = Instant::now();
+#[inline(always)]
+fn failed(status: i32, message: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!(
+            {
+                "status": status,
+                "message": message,
+                "pp": null
+            }
+        ))
+}
 
+/// Parse query data, and get the beatmap it requests
+async fn get_request_beatmap(
+    req: &HttpRequest,
+    glob: &Glob,
+) -> Result<(CalcData, Data<PPbeatmap>), HttpResponse> {
     // Parse query data
     let mut data = match Query::<CalcData>::from_query(&req.query_string()) {
         Ok(Query(q)) => q,
         Err(err) => {
-            return failed(0, err.to_string().as_str());
+            return Err(failed(0, err.to_string().as_str()));
         }
     };
 
     // We need any one of these
     if data.md5.is_none() && data.bid.is_none() && data.sid.is_none() {
-        return failed(
+        return Err(failed(
             0,
             "invalid requests, we must have one of: (md5, bid, sid + filename)",
-        );
+        ));
     };
 
-    let mut md5 = data.md5.clone();
-    let bid = data.bid;
-
     // If we have md5 input
-    if let Some(ref mut md5) = md5 {
+    if let Some(ref mut md5) = data.md5 {
         // Check md5
         if md5.len() != 32 {
-            return failed(0, "invalid md5");
+            return Err(failed(0, "invalid md5"));
         }
         // Safe it
         *md5 = peace_utils::common::safe_string(md5.clone());
     };
 
+    // Before the beatmap is fetched
+    if let Err(err) = calculator::validate_rate(&data) {
+        return Err(failed(0, &err));
+    };
+
     // get beatmap
-    let beatmap =
-        match calculator::get_beatmap(md5.clone(), bid, data.sid, data.file_name.clone(), &glob)
-            .await
-        {
-            Some(b) => b,
-            None => return failed(0, "cannot found beatmap"),
+    let beatmap = match calculator::get_beatmap(
+        data.md5.clone(),
+        data.bid,
+        data.sid,
+        data.file_name.clone(),
+        glob,
+    )
+    .await
+    {
+        Ok(b) => b,
+        Err(err) => {
+            return Err(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(err.json()))
+        }
+    };
+
+    // Algorithms reading the stored .osu file need its md5
+    if data.md5.is_none() {
+        let index = &glob.beatmap_index;
+        data.md5 = match (data.bid, data.sid, data.file_name.as_ref()) {
+            (Some(bid), _, _) => index.get_by_bid(bid, true).await,
+            (None, Some(sid), Some(file_name)) => index.get_by_sid_file(sid, file_name, true).await,
+            _ => None,
         };
+    };
+
+    Ok((data, beatmap))
+}
+
+/// The requested beatmap, at the requested rate
+#[inline(always)]
+async fn get_calc_beatmap(
+    req: &HttpRequest,
+    glob: &Glob,
+) -> Result<(CalcData, Data<PPbeatmap>), HttpResponse> {
+    let (data, beatmap) = get_request_beatmap(req, glob).await?;
+    Ok(calculator::prepare_beatmap(beatmap, data))
+}
+
+/// Mods of the request, not the ones a `rate` map is calculated with
+#[inline(always)]
+fn requested_mods(data: &CalcData) -> u32 {
+    data.requested_mods.or(data.mods).unwrap_or(0)
+}
+
+/// Clock rate the request is calculated with
+#[inline(always)]
+fn clock_rate(data: &CalcData) -> f32 {
+    data.rate
+        .unwrap_or_else(|| calculator::mods_clock_rate(data.mods.unwrap_or(0)))
+}
+
+/// GET "/api/algorithms"
+#[get("/algorithms")]
+pub async fn algorithms(glob: Data<Glob>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(glob.algorithms.list().await)
+}
+
+// calculate pp (used by peace)
+#[get("/calc")]
+pub async fn calculate_pp(req: HttpRequest, glob: Data<Glob>) -> HttpResponse {
+    let get_raw = |result: &PpResult| {
+        json!({
+            "aim": result.raw.aim.unwrap_or(0.0),
+            "spd": result.raw.spd.unwrap_or(0.0),
+            "acc": result.raw.acc.unwrap_or(0.0),
+            "str": result.raw.str.unwrap_or(0.0),
+            "total": result.raw.total,
+        })
+    };
+    let start = Instant::now();
+
+    let (data, beatmap) = match get_request_beatmap(&req, &glob).await {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+    let PreparedScore {
+        beatmap,
+        data,
+        simulated,
+        scoring,
+        algorithm,
+    } = match calculator::prepare_score(beatmap, data, &glob.algorithms).await {
+        Ok(p) => p,
+        Err(err) => {
+            return HttpResponse::Ok()
+                .content_type("application/json")
+                .body(err.json())
+        }
+    };
+
+    let algorithm_failed = |err: String| {
+        failed(
+            0,
+            &format!("algorithm {} failed: {}", algorithm.name(), err),
+        )
+    };
 
     // Get it, calculate.
-    let result = calculator::calculate_pp(&beatmap, &data).await;
+    let result = match algorithm.calculate(&beatmap, &data).await {
+        Ok(r) => r,
+        Err(err) => return algorithm_failed(err),
+    };
+    if let Err(err) = calculator::validate_combo(&data, &result) {
+        return HttpResponse::Ok()
+            .content_type("application/json")
+            .body(err.json());
+    };
 
     let mut value = json!({
         "status": 1,
         "message": "done",
         "mode": result.mode,
-        "mods": result.mods,
+        "mods": requested_mods(&data),
         "pp": result.pp(),
         "stars": result.attributes.stars(),
+        "rate": clock_rate(&data),
+        "algo": algorithm.name(),
+        "scoring": scoring,
+        "accuracy": calculator::play_accuracy(&beatmap, &data).or(data.acc),
+        "simulated": simulated.map(|s| json!({
+            "strategy": s.strategy.unwrap_or_default(),
+            "n300": s.n300,
+            "n100": s.n100,
+            "n50": s.n50,
+            "miss": s.miss,
+        })),
         "acc_list": Null
     });
 
     // If need, calculate acc list..
     if data.acc_list.is_some() && data.acc_list.unwrap() > 0 {
-        value["acc_list"] = calculator::calculate_acc_list(&beatmap, &data).await;
+        let acc_list = if algorithm.name() == algorithm::PEACE_PERFORMANCE {
+            Ok(calculator::calculate_acc_list(&beatmap, &data).await)
+        } else {
+            algorithm::calculate_acc_list(algorithm.as_ref(), &beatmap, &data).await
+        };
+        value["acc_list"] = match acc_list {
+            Ok(list) => list,
+            Err(err) => return algorithm_failed(err),
+        };
     };
 
-    // If need, calculate no_miss
+    // If need, calculate no_miss (projected full combo)
     if data.no_miss.is_some() && data.no_miss.unwrap() > 0 {
-        data.miss = Some(0);
-        let no_miss_result = calculator::calculate_pp(&beatmap, &data).await;
+        let fc = calculator::if_fc_data(&beatmap, &data);
+        let no_miss_result = match algorithm.calculate(&beatmap, &fc).await {
+            Ok(r) => r,
+            Err(err) => return algorithm_failed(err),
+        };
         let json = json!({
             "pp": no_miss_result.pp(),
-            "raw": get_raw(&no_miss_result)
+            "stars": no_miss_result.attributes.stars(),
+            "raw": get_raw(&no_miss_result),
+            "n300": fc.n300,
+            "n100": fc.n100,
+            "n50": fc.n50,
+            "miss": 0,
+            "acc": fc.acc,
+            "combo": no_miss_result.attributes.max_combo(),
         });
         value["no_miss"] = json;
     };
 
+    // If need, calculate with other algorithms too
+    if let Some(compare) = &data.compare {
+        let names = match compare.as_str() {
+            "all" => glob.algorithms.names().await,
+            list => list
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        };
+        let mut compared = serde_json::Map::new();
+        for name in names {
+            let other = match glob.algorithms.get(Some(&name)).await {
+                Some(a) => a,
+                None => return failed(0, &format!("unknown algorithm: {}", name)),
+            };
+            // One algorithm failing doesn't fail the others
+            let value = match other.calculate(&beatmap, &data).await {
+                Ok(r) => json!({
+                    "pp": r.pp(),
+                    "stars": r.attributes.stars(),
+                    "raw": get_raw(&r),
+                }),
+                Err(err) => json!({ "error": err }),
+            };
+            compared.insert(name, value);
+        }
+        value["compare"] = Value::Object(compared);
+    };
+
     let end = start.elapsed();
     info!(
         "[calculate_pp] Beatmap {:?}({:?}) calculate done in: {:?}",
-        md5, bid, end
+        data.md5, data.bid, end
     );
 
     if data.simple.is_some() && data.simple.unwrap() > 0 {
@@ -133,3 +293,259 @@ pub async fn calculate_pp(req: HttpRequest, glob: Data<Glob>) -> HttpResponse {
             .body(value)
     }
 }
+
+/// GET "/api/calc/gradual"
+///
+/// Stars and pp at every `stride` objects or every `time_step` ms (at least 1ms),
+/// by default at `gradual_max_points` points spread over the beatmap.
+/// Longer beatmaps get fewer points, `points * objects` is at most `gradual_max_work`
+#[get("/calc/gradual")]
+pub async fn calculate_gradual(req: HttpRequest, glob: Data<Glob>) -> HttpResponse {
+    let start = Instant::now();
+    let (data, beatmap) = match get_calc_beatmap(&req, &glob).await {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let config = &glob.local_config.data;
+    let max_points = calculator::gradual_max_points(
+        beatmap.hit_objects.len(),
+        config.gradual_max_points,
+        config.gradual_max_work,
+    );
+    let points = calculator::gradual_points(&beatmap, data.stride, data.time_step, max_points);
+    let curve = calculator::calculate_gradual(&beatmap, &data, &points).await;
+
+    info!(
+        "[calculate_gradual] Beatmap {:?}({:?}) {} points calculate done in: {:?}",
+        data.md5,
+        data.bid,
+        points.len(),
+        start.elapsed()
+    );
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({
+            "status": 1,
+            "message": "done",
+            "mode": data.mode,
+            "mods": requested_mods(&data),
+            "rate": clock_rate(&data),
+            "objects": beatmap.hit_objects.len(),
+            "points": curve,
+        }))
+}
+
+/// GET "/api/calc/mods"
+///
+/// Stars and max pp of the map with each mod combination (default: a standard set),
+/// optional pp at `accs`
+#[get("/calc/mods")]
+pub async fn calculate_mods_table(req: HttpRequest, glob: Data<Glob>) -> HttpResponse {
+    let start = Instant::now();
+    let (data, beatmap) = match get_calc_beatmap(&req, &glob).await {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    // Combinations have their own rate mods
+    if data.rate.is_some() {
+        return failed(0, "rate is not supported by the mods table");
+    };
+
+    let combinations = match &data.mods_list {
+        Some(list) => {
+            let mut combinations = Vec::new();
+            for s in list.split(',').filter(|s| !s.trim().is_empty()) {
+                match calculator::parse_mods(s) {
+                    Some(mods) => combinations.push(mods),
+                    None => return failed(0, &format!("invalid mods: {}", s)),
+                }
+            }
+            combinations
+        }
+        None => calculator::DEFAULT_MOD_COMBINATIONS.to_vec(),
+    };
+    if combinations.is_empty() || combinations.len() > calculator::MAX_MOD_COMBINATIONS {
+        return failed(
+            0,
+            &format!(
+                "mods_list must have 1 to {} combinations",
+                calculator::MAX_MOD_COMBINATIONS
+            ),
+        );
+    };
+    let accs = match &data.accs {
+        Some(accs) => match accs
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| {
+                s.trim()
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|a| *a >= 0.0 && *a <= 100.0)
+            })
+            .collect::<Option<Vec<f32>>>()
+        {
+            Some(accs) => accs,
+            None => return failed(0, "invalid accs"),
+        },
+        None => Vec::new(),
+    };
+    if accs.len() > calculator::MAX_MODS_TABLE_ACCS {
+        return failed(
+            0,
+            &format!(
+                "accs can have at most {} accuracies",
+                calculator::MAX_MODS_TABLE_ACCS
+            ),
+        );
+    };
+
+    let table =
+        calculator::calculate_mods_table(&beatmap, data.mode.unwrap_or(4), &combinations, &accs)
+            .await;
+
+    info!(
+        "[calculate_mods_table] Beatmap {:?}({:?}) {} mod combinations calculate done in: {:?}",
+        data.md5,
+        data.bid,
+        combinations.len(),
+        start.elapsed()
+    );
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(json!({
+            "status": 1,
+            "message": "done",
+            "mode": data.mode,
+            "table": table,
+        }))
+}
+
+/// GET "/api/calc/strains"
+///
+/// Strain peaks section by section, for difficulty graphs
+#[get("/calc/strains")]
+pub async fn calculate_strains(req: HttpRequest, glob: Data<Glob>) -> HttpResponse {
+    let start = Instant::now();
+    let (data, beatmap) = match get_calc_beatmap(&req, &glob).await {
+        Ok(r) => r,
+        Err(resp) => return resp,
+    };
+
+    let mods = data.mods.unwrap_or(0);
+    let mode = calculator::calc_mode(&beatmap, &data);
+    let mut value = calculator::strain_timeline(&beatmap, mode, mods);
+    value["status"] = json!(1);
+    value["message"] = json!("done");
+    value["mods"] = json!(requested_mods(&data));
+    value["rate"] = json!(clock_rate(&data));
+
+    info!(
+        "[calculate_strains] Beatmap {:?}({:?}) strains calculate done in: {:?}",
+        data.md5,
+        data.bid,
+        start.elapsed()
+    );
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(value)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileRequest {
+    /// Best scores of the player
+    pub scores: Option<Vec<ProfileScore>>,
+    /// Count of all ranked scores (for bonus pp), default the count of `scores`
+    pub score_count: Option<usize>,
+    /// A hypothetical new score, returns the profile with it and the delta
+    pub new_score: Option<ProfileScore>,
+    /// Player stats calculated by Peace, instead of `scores`
+    #[cfg(feature = "with_peace")]
+    pub player_id: Option<i32>,
+    #[cfg(feature = "with_peace")]
+    pub mode: Option<u8>,
+}
+
+/// POST "/api/profile"
+///
+/// Weighted total pp (0.95^n), bonus pp and weighted accuracy of a player's scores
+#[post("/profile")]
+#[cfg_attr(not(feature = "with_peace"), allow(unused_variables))]
+pub async fn calculate_profile(glob: Data<Glob>, body: Bytes) -> HttpResponse {
+    let data = match serde_json::from_slice::<ProfileRequest>(&body) {
+        Ok(d) => d,
+        Err(err) => return failed(0, err.to_string().as_str()),
+    };
+
+    #[cfg(feature = "with_peace")]
+    if let Some(player_id) = data.player_id {
+        if data.new_score.is_some() {
+            return failed(
+                0,
+                "new_score is not supported with player_id, send the player's scores instead",
+            );
+        };
+        let mode = match peace_constants::GameMode::parse(data.mode.unwrap_or(0)) {
+            Some(m) => m,
+            None => return failed(0, "invalid mode"),
+        };
+        // Peace only gives the totals, so there is no delta for a new score
+        return match peace_utils::peace::player_calculate_pp_acc(
+            player_id,
+            &mode.full_name(),
+            &glob.database,
+        )
+        .await
+        {
+            Some(result) => HttpResponse::Ok()
+                .content_type("application/json")
+                .body(json!({
+                    "status": 1,
+                    "message": "done",
+                    "player_id": player_id,
+                    "current": {
+                        "pp": result.pp,
+                        "acc": result.acc,
+                    },
+                })),
+            None => failed(0, "cannot calculate player stats"),
+        };
+    };
+
+    let scores = match data.scores {
+        Some(scores) => scores,
+        None => return failed(0, "invalid requests, we must have: scores"),
+    };
+    if !scores
+        .iter()
+        .chain(data.new_score.iter())
+        .all(|s| s.is_valid())
+    {
+        return failed(
+            0,
+            "invalid score, pp must not be negative and acc must be in 0 ~ 100",
+        );
+    };
+
+    let current = profile::profile_stats(&scores, data.score_count);
+    let mut value = json!({
+        "status": 1,
+        "message": "done",
+        "current": current,
+    });
+    if let Some(new_score) = data.new_score {
+        let mut with_new = scores;
+        with_new.push(new_score);
+        let new = profile::profile_stats(&with_new, data.score_count.map(|c| c + 1));
+        value["delta"] = json!({
+            "pp": new.pp - current.pp,
+            "acc": new.acc - current.acc,
+        });
+        value["with_new_score"] = json!(new);
+    };
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(value)
+}
@@ -7,7 +7,7 @@ use {
     tokio::sync::mpsc::UnboundedSender,
 };
 
-use crate::objects::Caches;
+use crate::objects::{glob::Glob, Caches};
 
 /// GET "/"
 #[get("/")]
@@ -40,6 +40,26 @@ pub async fn server_stop(sender: Data<UnboundedSender<Option<Server>>>) -> HttpR
 pub async fn clear_cache(caches: Data<Caches>) -> HttpResponse {
     let start = Instant::now();
     caches.pp_beatmap_cache.write().await.clear();
+    caches.negative_cache.write().await.clear();
     let end = start.elapsed();
     HttpResponse::Ok().body(format!("clear_cache done in: {:?}", end))
 }
+
+/// GET "/beatmap_sources"
+#[get("/beatmap_sources")]
+pub async fn beatmap_sources(glob: Data<Glob>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(glob.downloader.health())
+}
+
+/// GET "/osu_api_limiter"
+#[get("/osu_api_limiter")]
+pub async fn osu_api_limiter(glob: Data<Glob>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(serde_json::json!({
+            "v1": glob.osu_api_limiter.stats().await,
+            "v2": glob.osu_api_v2_limiter.stats().await,
+        }))
+}
@@ -1,8 +1,10 @@
+mod admin;
 mod api;
 mod debug;
 mod default;
+mod osu;
 
-use ntex::web::{scope, ServiceConfig};
+use ntex::web::{scope, types::PayloadConfig, ServiceConfig};
 
 use crate::settings::model::LocalConfigData;
 
@@ -11,6 +13,8 @@ use crate::settings::model::LocalConfigData;
 pub fn init(cfg: &mut ServiceConfig, settings: &LocalConfigData) {
     init_default(cfg);
     init_api(cfg);
+    init_admin(cfg, settings);
+    init_osu(cfg);
 
     // !warning: only debug!
     if settings.debug == true {
@@ -21,7 +25,41 @@ pub fn init(cfg: &mut ServiceConfig, settings: &LocalConfigData) {
 /// Routes for api
 fn init_api(cfg: &mut ServiceConfig) {
     use api::*;
-    cfg.service(scope("/api").service(index).service(calculate_pp));
+    cfg.service(
+        scope("/api")
+            .service(index)
+            .service(algorithms)
+            .service(calculate_pp)
+            .service(calculate_gradual)
+            .service(calculate_mods_table)
+            .service(calculate_strains)
+            .service(calculate_profile),
+    );
+}
+
+/// Routes for stored .osu files
+fn init_osu(cfg: &mut ServiceConfig) {
+    use osu::*;
+    cfg.service(
+        scope("/osu")
+            .service(osu_file_by_md5)
+            .service(osu_file_by_bid),
+    );
+}
+
+/// Routes for admin, need admin_key
+fn init_admin(cfg: &mut ServiceConfig, settings: &LocalConfigData) {
+    use admin::*;
+    cfg.service(
+        scope("/admin")
+            .app_data(PayloadConfig::new(settings.upload_max_size))
+            .service(offline_status)
+            .service(offline_switch)
+            .service(upload)
+            .service(prefetch)
+            .service(prefetch_jobs)
+            .service(prefetch_job),
+    );
 }
 
 fn init_debug(cfg: &mut ServiceConfig) {
@@ -29,6 +67,8 @@ fn init_debug(cfg: &mut ServiceConfig) {
     cfg.service(index);
     cfg.service(server_stop);
     cfg.service(clear_cache);
+    cfg.service(beatmap_sources);
+    cfg.service(osu_api_limiter);
 }
 
 /// Routes for default
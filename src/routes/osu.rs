@@ -0,0 +1,125 @@
+use {
+    chrono::{DateTime, Utc},
+    ntex::{
+        http::StatusCode,
+        web::{
+            get,
+            types::{Data, Path},
+            HttpRequest, HttpResponse,
+        },
+    },
+    serde_json::json,
+    tokio_util::io::ReaderStream,
+};
+
+use crate::{
+    objects::calculator::{self, GetBeatmapError},
+    Glob,
+};
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// GET "/osu/{bid}"
+#[get("/{bid}")]
+pub async fn osu_file_by_bid(req: HttpRequest, glob: Data<Glob>, bid: Path<i32>) -> HttpResponse {
+    serve_osu_file(&req, &glob, None, Some(bid.into_inner())).await
+}
+
+/// GET "/osu/md5/{md5}"
+#[get("/md5/{md5}")]
+pub async fn osu_file_by_md5(
+    req: HttpRequest,
+    glob: Data<Glob>,
+    md5: Path<String>,
+) -> HttpResponse {
+    let md5 = md5.into_inner();
+    if md5.len() != 32 {
+        return HttpResponse::BadRequest()
+            .content_type("application/json")
+            .body(json!({"status": 0, "message": "invalid md5"}));
+    };
+    let md5 = peace_utils::common::safe_string(md5.to_lowercase());
+    serve_osu_file(&req, &glob, Some(md5), None).await
+}
+
+/// Send the stored .osu file, with ETag (md5) and Last-Modified
+pub async fn serve_osu_file(
+    req: &HttpRequest,
+    glob: &Glob,
+    md5: Option<String>,
+    bid: Option<i32>,
+) -> HttpResponse {
+    let md5 = match calculator::resolve_osu_file(md5, bid, glob).await {
+        Ok(md5) => md5,
+        Err(err) => {
+            let status = match err.error {
+                GetBeatmapError::NotFound | GetBeatmapError::FileNotFound => StatusCode::NOT_FOUND,
+                GetBeatmapError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                GetBeatmapError::Offline
+                | GetBeatmapError::Unavailable
+                | GetBeatmapError::ParseError => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            return HttpResponse::build(status)
+                .content_type("application/json")
+                .body(err.json());
+        }
+    };
+    let path = format!("{}/{}.osu", glob.local_config.data.osu_files_dir, md5);
+
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(err) => {
+            warn!(
+                "[osu_file] Failed to open .osu file '{}', err: {:?}",
+                path, err
+            );
+            return HttpResponse::NotFound()
+                .content_type("application/json")
+                .body(json!({"status": 0, "message": "cannot read .osu file"}));
+        }
+    };
+
+    let etag = format!("\"{}\"", md5);
+    let modified = file
+        .metadata()
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+        .map(DateTime::<Utc>::from);
+
+    // Conditional requests
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let not_modified = match (header("If-None-Match"), header("If-Modified-Since")) {
+        (Some(tags), _) => etag_matches(tags, &etag),
+        (None, Some(since)) => match (DateTime::parse_from_rfc2822(since), modified) {
+            (Ok(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+            _ => false,
+        },
+        _ => false,
+    };
+
+    let mut resp = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    resp.header("ETag", etag.as_str());
+    if let Some(modified) = modified {
+        resp.header("Last-Modified", modified.format(HTTP_DATE).to_string());
+    };
+    if not_modified {
+        return resp.finish();
+    };
+
+    resp.content_type("text/plain; charset=utf-8")
+        .streaming(ReaderStream::new(file))
+}
+
+/// `If-None-Match` is `*` or a list of entity tags, weak (`W/"..."`) ones compare equal too
+#[inline(always)]
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
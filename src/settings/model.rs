@@ -20,14 +20,28 @@ pub struct LocalConfigData {
 
     pub env: String,
     pub debug: bool,
+    pub admin_key: String,
+    pub upload_max_size: usize,
+    pub offline: bool,
     pub osu_files_dir: String,
+    pub beatmap_index_file: String,
+    pub beatmap_index_save_interval: u64,
     pub recalculate_osu_file_md5: bool,
+    pub watch_osu_files_dir: bool,
+    pub watch_debounce: u64,
     pub preload_osu_files: bool,
+    pub gradual_max_points: usize,
+    pub gradual_max_work: usize,
     pub beatmap_cache_max: i32,
     pub beatmap_cache_timeout: u64,
     pub auto_clean_cache: bool,
     pub auto_clean_interval: u64,
     pub auto_pp_recalculate: AutoPPRecalculate,
+    pub beatmap_download: BeatmapDownload,
+    pub negative_cache: NegativeCache,
+    pub prefetch: Prefetch,
+    pub algorithms: Algorithms,
+    pub osu_api: OsuApiSettings,
     pub server: Server,
     pub logger: Logger,
     #[serde(rename = "prometheus")]
@@ -69,3 +83,79 @@ pub struct AutoPPRecalculate {
     pub interval: u64,
     pub max_retry: i32,
 }
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BeatmapDownload {
+    pub max_failures: u32,
+    pub cooldown: u64,
+    pub sources: Vec<BeatmapSource>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BeatmapSource {
+    pub name: String,
+    pub url: String,
+    pub timeout: u64,
+    pub retry: u32,
+    pub retry_backoff: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NegativeCache {
+    pub enabled: bool,
+    pub max: usize,
+    pub not_found_ttl: u64,
+    pub parse_error_ttl: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OsuApiSettings {
+    pub version: u8,
+    pub v2: OsuApiV2Settings,
+    pub rate_limit: RateLimit,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimit {
+    pub enabled: bool,
+    pub requests_per_minute: u32,
+    pub burst: u32,
+    pub max_wait: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OsuApiV2Settings {
+    pub client_id: i32,
+    pub client_secret: String,
+    pub base_url: String,
+    pub token_url: String,
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Algorithms {
+    pub default: String,
+    pub auto_select_by_mods: bool,
+    pub relax: FormulaVariant,
+    pub autopilot: FormulaVariant,
+    pub plugins_dir: String,
+    pub watch_plugins_dir: bool,
+    pub watch_plugins_debounce: u64,
+    pub plugin_max_operations: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FormulaVariant {
+    pub enabled: bool,
+    pub aim_weight: f32,
+    pub speed_weight: f32,
+    pub acc_weight: f32,
+    pub miss_penalty: f32,
+    pub multiplier: f32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Prefetch {
+    pub interval: u64,
+    pub max_targets: usize,
+}
@@ -1,12 +1,18 @@
-use ntex::web::types::Data;
-use tokio::fs::File as AsyncFile;
 use colored::Colorize;
+use ntex::web::types::Data;
 use peace_performance::Beatmap as PPbeatmap;
 use std::cmp::min;
+use std::path::PathBuf;
 use std::time::Instant;
 use std::{fs, io};
+use tokio::fs::File as AsyncFile;
 
-use crate::objects::{Caches, PPbeatmapCache};
+use crate::objects::{
+    beatmap_index::BeatmapIndex, importer, osu_files::OsuFileMetadata, watcher, Caches,
+    PPbeatmapCache,
+};
+use crate::settings::LocalConfig;
+use crate::Glob;
 
 #[inline(always)]
 pub fn check_is_osu_file(entry: &Result<fs::DirEntry, io::Error>) -> u8 {
@@ -100,42 +106,47 @@ pub async fn preload_osu_files(osu_files_dir: &String, max_load: i32, caches: &D
     )
 }
 
-#[inline(always)]
-pub fn recalculate_osu_file_md5(osu_files_dir: &String) {
-    let mut renamed = 0;
-    let mut done = 0;
-    let mut error = 0;
-    let (entries, total) = listing_osu_files(osu_files_dir);
-    println!("\n  Recalculating MD5 file names...");
+/// Index `<md5>.osu` files that are not indexed yet, other .osu files are verified,
+/// renamed to their md5 and indexed the same way the osu dir watcher does it
+pub async fn index_osu_files(glob: &Glob) {
+    let index = &glob.beatmap_index;
+    let (entries, total) = listing_osu_files(&glob.local_config.data.osu_files_dir);
+    println!("\n  Indexing .osu files...");
     let bar = peace_utils::common::progress_bar(total as u64);
+    let (mut indexed, mut renamed) = (0, 0);
     let start = Instant::now();
-    for entry in entries {
+    for entry in entries.into_iter().flatten() {
         bar.inc(1);
-        if let Some(entry) = entry {
-            let md5 = match peace_utils::async_file::calc_file_md5(entry.path()) {
-                Ok(md5) => md5,
-                Err(_) => {
-                    error += 1;
-                    continue;
-                }
-            };
-            if fs::rename(entry.path(), format!("{}/{}.osu", osu_files_dir, md5)).is_err() {
-                error += 1;
-            } else {
+        let path = entry.path();
+        let md5 = match watcher::md5_file_stem(&path) {
+            Some(md5) if path.file_stem().and_then(|s| s.to_str()) == Some(md5.as_str()) => md5,
+            _ => {
+                watcher::handle_osu_file(path, glob).await;
                 renamed += 1;
+                continue;
             }
-            done += 1;
-        }
+        };
+        if index.contains(&md5).await {
+            continue;
+        };
+        if let Ok(bytes) = tokio::fs::read(&path).await {
+            let metadata = OsuFileMetadata::from_bytes(&bytes);
+            if index.insert_metadata(&md5, &metadata, None, None).await {
+                indexed += 1;
+            };
+        };
     }
     bar.finish();
+    if indexed > 0 || renamed > 0 {
+        index.save().await;
+    };
     println!(
-        "{}\n",
+        "\n{}\n",
         format!(
-            "> Done, \n> Success / Done / Total: {} / {} / {}; \n> Errors: {}; \n> time spent: {:?}",
+            "> Beatmaps has indexed, \n> New: {}, Not named as md5: {}, Total: {}; \n> time spent: {:?}",
+            indexed,
             renamed,
-            done,
             total,
-            error,
             start.elapsed()
         )
         .bright_yellow()
@@ -143,7 +154,7 @@ pub fn recalculate_osu_file_md5(osu_files_dir: &String) {
 }
 
 #[inline(always)]
-pub fn checking_osu_dir(osu_files_dir: &String, recalculate_md5: bool) {
+pub async fn checking_osu_dir(osu_files_dir: &String, recalculate_md5: bool, index: &BeatmapIndex) {
     if osu_files_dir == "" {
         println!(
             "{}",
@@ -152,6 +163,34 @@ pub fn checking_osu_dir(osu_files_dir: &String, recalculate_md5: bool) {
                 .red()
         );
     } else if recalculate_md5 {
-        recalculate_osu_file_md5(osu_files_dir);
+        importer::recalculate_osu_files_md5(osu_files_dir, index).await;
     };
 }
+
+/// `import <paths...>`: copy every difficulty from osu! Songs folders, .osz archives
+/// or .osu files into osu_files_dir as `<md5>.osu`
+pub async fn run_import(cfg: &LocalConfig, args: &[String]) {
+    if args.is_empty() {
+        println!(
+            "{}",
+            "> Usage: pp-server import <osu! Songs folder | .osz dir | .osz | .osu>..."
+                .bold()
+                .red()
+        );
+        return;
+    };
+    let paths: Vec<PathBuf> = args.iter().map(PathBuf::from).collect();
+    let index = BeatmapIndex::load(&cfg.data.beatmap_index_file, cfg.data.beatmap_cache_timeout);
+    println!(
+        "{}",
+        format!(
+            "\n> Importing {:?} into '{}'...",
+            paths, cfg.data.osu_files_dir
+        )
+        .bright_yellow()
+    );
+    let start = Instant::now();
+    importer::import_paths(&paths, &cfg.data.osu_files_dir, &index)
+        .await
+        .print(start);
+}